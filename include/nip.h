@@ -0,0 +1,37 @@
+/*
+ * C interface to nip, built as libnip by `cargo build`. See src/nip_ffi.rs for the
+ * conventions on ownership and error reporting.
+ */
+#ifndef NIP_H
+#define NIP_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct NipIndexHandle NipIndexHandle;
+
+NipIndexHandle *nip_index_load(const char *api_host, uint16_t api_port, const char *url);
+void nip_index_free(NipIndexHandle *handle);
+
+size_t nip_index_ref_count(const NipIndexHandle *handle);
+char *nip_index_ref_name(const NipIndexHandle *handle, size_t n);
+char *nip_index_ref_target(const NipIndexHandle *handle, size_t n);
+
+int nip_fetch(NipIndexHandle *handle, const char *repo_path, const char *git_hash,
+              const char *ref_name);
+int nip_push(NipIndexHandle *handle, const char *repo_path, const char *src,
+             const char *dst, int force);
+char *nip_index_publish(NipIndexHandle *handle);
+
+const char *nip_last_error(void);
+void nip_string_free(char *s);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* NIP_H */
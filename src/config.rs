@@ -0,0 +1,721 @@
+//! nip's layered configuration. Every setting is resolved from the following sources, later ones
+//! taking precedence:
+//!
+//! 1. Built-in defaults
+//! 2. `~/.config/nip/config.toml`
+//! 3. `git config nip.*`
+//! 4. `git config remote.<name>.nip-*` for the remote being worked with
+//! 5. `NIP_*` environment variables
+//! 6. Command line flags (applied by the binaries via `NipConfig::apply`)
+use failure::Error;
+use git2::{Config, ErrorCode};
+use ipfs_api::IpfsClient;
+use log::LevelFilter;
+
+use std::{convert::TryFrom, env, fs, io, path::PathBuf, str::FromStr};
+
+use crate::ipns_cache::DEFAULT_IPNS_CACHE_TTL;
+
+/// Pushes uploading more than this many bytes need confirmation by default
+pub static DEFAULT_LARGE_PUSH_THRESHOLD: u64 = 500 * 1024 * 1024;
+
+/// The fully resolved nip configuration
+#[derive(Clone, Debug, PartialEq)]
+pub struct NipConfig {
+    /// Host of the IPFS API
+    pub api_host: String,
+    /// Port of the IPFS API
+    pub api_port: u16,
+    /// Default timeout for network operations that nip can bound, in seconds
+    pub timeout: u64,
+    /// How long IPNS resolutions are reused, in seconds; 0 disables the cache
+    pub ipns_cache_ttl: u64,
+    /// The IPFS key IPNS remotes are published under; `None` leaves it up to nip_core
+    pub ipns_key: Option<String>,
+    /// How long published IPNS records stay valid, as a duration like "720h"
+    pub ipns_lifetime: Option<String>,
+    /// How long resolvers may cache published IPNS records, as a duration like "5m"
+    pub ipns_ttl: Option<String>,
+    /// How many previous index generations stay pinned after a push; `None` disables unpinning
+    pub pinned_generations: Option<u32>,
+    /// Pushes bigger than this many bytes need confirmation; 0 disables the check
+    pub large_push_threshold: u64,
+    /// Skip the large push check
+    pub force_large: bool,
+    /// Allow forced pushes that discard commits without asking
+    pub allow_force: bool,
+    /// What to do about fetched commits and tags without a valid signature
+    pub signature_policy: SignaturePolicy,
+    /// GnuPG home directory with the keys signatures are checked against; `None` uses the
+    /// user's. SSH signatures are always checked against `gpg.ssh.allowedSignersFile`.
+    pub signature_keyring: Option<PathBuf>,
+    /// Default log level, `RUST_LOG` still takes precedence
+    pub log_level: LevelFilter,
+    /// Keep a full trace of every remote helper session in `.git/nip/logs/`
+    pub session_log: bool,
+    /// Where to write a JSON receipt describing each successful push; relative paths are taken
+    /// relative to the repo's working directory
+    pub push_receipt: Option<PathBuf>,
+    /// Whether pushes point the remote's URL at the new index
+    pub update_remote_url: UrlUpdatePolicy,
+    /// Append the index every push produced to the `refs/notes/nip` note of each pushed commit
+    pub push_notes: bool,
+    /// MFS directory on the IPFS daemon that receives a copy of every pushed branch's tree, in a
+    /// subdirectory named after the branch
+    pub mfs_mirror: Option<String>,
+}
+
+/// A single configuration layer; unset fields leave the value from lower layers untouched.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct PartialNipConfig {
+    /// The API endpoint as a multiaddr or `host:port`; `api_host`/`api_port` take precedence
+    pub api: Option<String>,
+    pub api_host: Option<String>,
+    pub api_port: Option<u16>,
+    pub timeout: Option<u64>,
+    pub ipns_cache_ttl: Option<u64>,
+    pub ipns_key: Option<String>,
+    pub ipns_lifetime: Option<String>,
+    pub ipns_ttl: Option<String>,
+    pub pinned_generations: Option<u32>,
+    pub large_push_threshold: Option<u64>,
+    pub force_large: Option<bool>,
+    pub allow_force: Option<bool>,
+    pub signature_policy: Option<String>,
+    pub signature_keyring: Option<String>,
+    pub log_level: Option<String>,
+    pub session_log: Option<bool>,
+    pub push_receipt: Option<String>,
+    pub update_remote_url: Option<String>,
+    pub push_notes: Option<bool>,
+    pub mfs_mirror: Option<String>,
+}
+
+/// How the helper treats fetched history that isn't signed by a trusted key
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SignaturePolicy {
+    /// Don't check signatures at all
+    Ignore,
+    /// Log a warning for every commit or tag without a valid signature
+    Warn,
+    /// Fail the fetch if any commit or tag lacks a valid signature
+    Require,
+}
+
+impl FromStr for SignaturePolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "ignore" => Ok(SignaturePolicy::Ignore),
+            "warn" => Ok(SignaturePolicy::Warn),
+            "require" => Ok(SignaturePolicy::Require),
+            other => bail!(
+                "Invalid signature policy {:?}, expected one of ignore, warn, require",
+                other
+            ),
+        }
+    }
+}
+
+/// What the helper does with the remote's URL once a push produced a new index
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UrlUpdatePolicy {
+    /// Rewrite the URL
+    Always,
+    /// Leave the URL alone, only recording and printing the new one
+    Never,
+    /// Ask on the terminal, leaving the URL alone if there's none
+    Prompt,
+}
+
+impl FromStr for UrlUpdatePolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "always" => Ok(UrlUpdatePolicy::Always),
+            "never" => Ok(UrlUpdatePolicy::Never),
+            "prompt" => Ok(UrlUpdatePolicy::Prompt),
+            other => bail!(
+                "Invalid remote URL update policy {:?}, expected one of always, never, prompt",
+                other
+            ),
+        }
+    }
+}
+
+impl Default for NipConfig {
+    fn default() -> Self {
+        Self {
+            api_host: "localhost".to_owned(),
+            api_port: 5001,
+            timeout: 30,
+            ipns_cache_ttl: DEFAULT_IPNS_CACHE_TTL,
+            ipns_key: None,
+            ipns_lifetime: None,
+            ipns_ttl: None,
+            pinned_generations: None,
+            large_push_threshold: DEFAULT_LARGE_PUSH_THRESHOLD,
+            force_large: false,
+            allow_force: false,
+            signature_policy: SignaturePolicy::Ignore,
+            signature_keyring: None,
+            log_level: LevelFilter::Info,
+            session_log: false,
+            push_receipt: None,
+            update_remote_url: UrlUpdatePolicy::Always,
+            push_notes: false,
+            mfs_mirror: None,
+        }
+    }
+}
+
+impl NipConfig {
+    /// Resolves the configuration from all layers except command line flags. `git_config` should
+    /// be the config of the repo nip is working with, or the global one if there's no repo;
+    /// `remote_name` selects the `remote.<name>.nip-*` overrides to use.
+    pub fn load(git_config: Option<&Config>, remote_name: Option<&str>) -> Result<Self, Error> {
+        let mut config = Self::default();
+
+        if let Some(file_layer) = PartialNipConfig::from_config_file()? {
+            config.apply(&file_layer)?;
+        }
+
+        if let Some(git_config) = git_config {
+            config.apply(&PartialNipConfig::from_git_config(git_config)?)?;
+
+            if let Some(remote_name) = remote_name {
+                config.apply(&PartialNipConfig::from_git_remote_config(
+                    git_config,
+                    remote_name,
+                )?)?;
+            }
+        }
+
+        config.apply(&PartialNipConfig::from_env()?)?;
+
+        Ok(config)
+    }
+
+    /// Overrides the settings `layer` specifies
+    pub fn apply(&mut self, layer: &PartialNipConfig) -> Result<(), Error> {
+        if let Some(ref api) = layer.api {
+            let (api_host, api_port) = parse_api_endpoint(api)?;
+            self.api_host = api_host;
+            self.api_port = api_port;
+        }
+        if let Some(ref api_host) = layer.api_host {
+            self.api_host = api_host.clone();
+        }
+        if let Some(api_port) = layer.api_port {
+            self.api_port = api_port;
+        }
+        if let Some(timeout) = layer.timeout {
+            self.timeout = timeout;
+        }
+        if let Some(ipns_cache_ttl) = layer.ipns_cache_ttl {
+            self.ipns_cache_ttl = ipns_cache_ttl;
+        }
+        if let Some(ref ipns_key) = layer.ipns_key {
+            self.ipns_key = Some(ipns_key.clone());
+        }
+        if let Some(ref ipns_lifetime) = layer.ipns_lifetime {
+            validate_duration(ipns_lifetime)?;
+            self.ipns_lifetime = Some(ipns_lifetime.clone());
+        }
+        if let Some(ref ipns_ttl) = layer.ipns_ttl {
+            validate_duration(ipns_ttl)?;
+            self.ipns_ttl = Some(ipns_ttl.clone());
+        }
+        if let Some(pinned_generations) = layer.pinned_generations {
+            self.pinned_generations = Some(pinned_generations);
+        }
+        if let Some(large_push_threshold) = layer.large_push_threshold {
+            self.large_push_threshold = large_push_threshold;
+        }
+        if let Some(force_large) = layer.force_large {
+            self.force_large = force_large;
+        }
+        if let Some(allow_force) = layer.allow_force {
+            self.allow_force = allow_force;
+        }
+        if let Some(ref signature_policy) = layer.signature_policy {
+            self.signature_policy = signature_policy.parse()?;
+        }
+        if let Some(ref signature_keyring) = layer.signature_keyring {
+            self.signature_keyring = Some(PathBuf::from(signature_keyring));
+        }
+        if let Some(ref log_level) = layer.log_level {
+            self.log_level = LevelFilter::from_str(log_level)
+                .map_err(|_| format_err!("Invalid log level {:?}", log_level))?;
+        }
+        if let Some(session_log) = layer.session_log {
+            self.session_log = session_log;
+        }
+        if let Some(ref push_receipt) = layer.push_receipt {
+            self.push_receipt = Some(PathBuf::from(push_receipt));
+        }
+        if let Some(ref update_remote_url) = layer.update_remote_url {
+            self.update_remote_url = update_remote_url.parse()?;
+        }
+        if let Some(push_notes) = layer.push_notes {
+            self.push_notes = push_notes;
+        }
+        if let Some(ref mfs_mirror) = layer.mfs_mirror {
+            self.mfs_mirror = Some(mfs_mirror.clone());
+        }
+
+        Ok(())
+    }
+
+    /// Instantiates an IPFS client for the configured API endpoint
+    pub fn ipfs_client(&self) -> Result<IpfsClient, Error> {
+        IpfsClient::new(&self.api_host, self.api_port).map_err(|e| {
+            format_err!(
+                "Invalid IPFS API endpoint {}:{}: {}",
+                self.api_host,
+                self.api_port,
+                e
+            )
+        })
+    }
+}
+
+impl PartialNipConfig {
+    /// The location of the user-level config file
+    pub fn config_file_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("nip").join("config.toml"))
+    }
+
+    /// Reads `~/.config/nip/config.toml` if it exists
+    pub fn from_config_file() -> Result<Option<Self>, Error> {
+        let path = match Self::config_file_path() {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => bail!("Could not read {}: {}", path.display(), e),
+        };
+
+        debug!("Reading config from {}", path.display());
+
+        toml::from_str(&contents)
+            .map(Some)
+            .map_err(|e| format_err!("Could not parse {}: {}", path.display(), e))
+    }
+
+    /// Reads the `nip.*` keys of `config`
+    pub fn from_git_config(config: &Config) -> Result<Self, Error> {
+        Ok(Self {
+            api: git_string(config, "nip.api")?,
+            api_host: git_string(config, "nip.apiHost")?,
+            api_port: git_u64(config, "nip.apiPort")?
+                .map(|port| to_port(port, "nip.apiPort"))
+                .transpose()?,
+            timeout: git_u64(config, "nip.timeout")?,
+            ipns_cache_ttl: git_u64(config, "nip.ipnsCacheTtl")?,
+            ipns_key: git_string(config, "nip.ipnsKey")?,
+            ipns_lifetime: git_string(config, "nip.ipnsLifetime")?,
+            ipns_ttl: git_string(config, "nip.ipnsTtl")?,
+            pinned_generations: git_u64(config, "nip.pinnedGenerations")?
+                .map(|count| to_u32(count, "nip.pinnedGenerations"))
+                .transpose()?,
+            large_push_threshold: git_u64(config, "nip.largePushThreshold")?,
+            force_large: git_bool(config, "nip.forceLarge")?,
+            allow_force: git_bool(config, "nip.allowForce")?,
+            signature_policy: git_string(config, "nip.signaturePolicy")?,
+            signature_keyring: git_string(config, "nip.signatureKeyring")?,
+            log_level: git_string(config, "nip.logLevel")?,
+            session_log: git_bool(config, "nip.sessionLog")?,
+            push_receipt: git_string(config, "nip.pushReceipt")?,
+            update_remote_url: git_string(config, "nip.updateRemoteUrl")?,
+            push_notes: git_bool(config, "nip.pushNotes")?,
+            mfs_mirror: git_string(config, "nip.mfsMirror")?,
+        })
+    }
+
+    /// Reads the `remote.<remote_name>.nip-*` keys of `config`
+    pub fn from_git_remote_config(config: &Config, remote_name: &str) -> Result<Self, Error> {
+        let key = |name: &str| format!("remote.{}.nip-{}", remote_name, name);
+
+        Ok(Self {
+            api: git_string(config, &key("api"))?,
+            api_host: git_string(config, &key("api-host"))?,
+            api_port: git_u64(config, &key("api-port"))?
+                .map(|port| to_port(port, &key("api-port")))
+                .transpose()?,
+            ipns_key: git_string(config, &key("ipns-key"))?,
+            ipns_lifetime: git_string(config, &key("ipns-lifetime"))?,
+            ipns_ttl: git_string(config, &key("ipns-ttl"))?,
+            pinned_generations: git_u64(config, &key("pinned-generations"))?
+                .map(|count| to_u32(count, &key("pinned-generations")))
+                .transpose()?,
+            allow_force: git_bool(config, &key("allow-force"))?,
+            signature_policy: git_string(config, &key("signature-policy"))?,
+            signature_keyring: git_string(config, &key("signature-keyring"))?,
+            push_receipt: git_string(config, &key("push-receipt"))?,
+            update_remote_url: git_string(config, &key("update-remote-url"))?,
+            push_notes: git_bool(config, &key("push-notes"))?,
+            mfs_mirror: git_string(config, &key("mfs-mirror"))?,
+            ..Default::default()
+        })
+    }
+
+    /// Reads the `NIP_*` environment variables
+    pub fn from_env() -> Result<Self, Error> {
+        Ok(Self {
+            api: env::var("NIP_API").ok(),
+            api_host: env::var("NIP_API_HOST").ok(),
+            api_port: env_parse("NIP_API_PORT")?,
+            timeout: env_parse("NIP_TIMEOUT")?,
+            ipns_cache_ttl: env_parse("NIP_IPNS_CACHE_TTL")?,
+            ipns_key: env::var("NIP_IPNS_KEY").ok(),
+            ipns_lifetime: env::var("NIP_IPNS_LIFETIME").ok(),
+            ipns_ttl: env::var("NIP_IPNS_TTL").ok(),
+            pinned_generations: env_parse("NIP_PINNED_GENERATIONS")?,
+            large_push_threshold: env_parse("NIP_LARGE_PUSH_THRESHOLD")?,
+            force_large: env_parse("NIP_FORCE_LARGE")?,
+            allow_force: env_parse("NIP_ALLOW_FORCE")?,
+            signature_policy: env::var("NIP_SIGNATURE_POLICY").ok(),
+            signature_keyring: env::var("NIP_SIGNATURE_KEYRING").ok(),
+            log_level: env::var("NIP_LOG_LEVEL").ok(),
+            session_log: env_parse("NIP_SESSION_LOG")?,
+            push_receipt: env::var("NIP_PUSH_RECEIPT").ok(),
+            update_remote_url: env::var("NIP_UPDATE_REMOTE_URL").ok(),
+            push_notes: env_parse("NIP_PUSH_NOTES")?,
+            mfs_mirror: env::var("NIP_MFS_MIRROR").ok(),
+        })
+    }
+}
+
+/// Turns an API endpoint into a host/port pair. Multiaddrs like the `Addresses.API` value of an
+/// IPFS config (`/ip4/127.0.0.1/tcp/5001`, `/dns4/example.com/tcp/5001/http`) and plain
+/// `host:port` pairs are accepted.
+pub fn parse_api_endpoint(endpoint: &str) -> Result<(String, u16), Error> {
+    if !endpoint.starts_with('/') {
+        let colon_idx = endpoint
+            .rfind(':')
+            .ok_or_else(|| format_err!("API endpoint {:?} is missing a port", endpoint))?;
+        let port = endpoint[colon_idx + 1..]
+            .parse()
+            .map_err(|e| format_err!("Invalid port in API endpoint {:?}: {}", endpoint, e))?;
+        return Ok((endpoint[..colon_idx].to_owned(), port));
+    }
+
+    let mut components = endpoint[1..].split('/');
+    let mut next = |what: &str| {
+        components
+            .next()
+            .filter(|component| !component.is_empty())
+            .ok_or_else(|| format_err!("Multiaddr {:?} is missing {}", endpoint, what))
+    };
+
+    let host = match next("a protocol")? {
+        "ip4" | "dns" | "dns4" | "dns6" => next("a host")?.to_owned(),
+        // IPv6 literals need brackets in URLs
+        "ip6" => format!("[{}]", next("a host")?),
+        other => bail!("Unsupported multiaddr protocol {:?} in {:?}", other, endpoint),
+    };
+
+    match next("a transport")? {
+        "tcp" => {}
+        other => bail!(
+            "Unsupported multiaddr transport {:?} in {:?}, only tcp can be used",
+            other,
+            endpoint
+        ),
+    }
+
+    let port = next("a port")?
+        .parse()
+        .map_err(|e| format_err!("Invalid port in multiaddr {:?}: {}", endpoint, e))?;
+
+    match components.next() {
+        None | Some("http") => {}
+        // ipfs-api 0.5 builds plain http:// URLs on a fixed HTTP connector
+        Some("https") => bail!(
+            "HTTPS API endpoints ({:?}) can't be used, the IPFS client only speaks plain HTTP",
+            endpoint
+        ),
+        Some(other) => bail!("Unsupported multiaddr component {:?} in {:?}", other, endpoint),
+    }
+
+    if let Some(extra) = components.next() {
+        bail!("Unexpected trailing component {:?} in {:?}", extra, endpoint);
+    }
+
+    Ok((host, port))
+}
+
+/// Pretty-prints a byte count with a binary unit, e.g. "12.3 MiB"
+pub fn format_size(bytes: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} {}", bytes, units[0])
+    } else {
+        format!("{:.1} {}", size, units[unit])
+    }
+}
+
+/// Makes sure `duration` is something IPFS will accept as a record lifetime or TTL, i.e. a
+/// sequence of numbers with units like "1h30m"
+fn validate_duration(duration: &str) -> Result<(), Error> {
+    if duration.is_empty() {
+        bail!("Durations must not be empty");
+    }
+
+    let mut rest = duration;
+    while !rest.is_empty() {
+        let unit_idx = rest
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(rest.len());
+        let unit_len = rest[unit_idx..]
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(rest.len() - unit_idx);
+
+        let unit = &rest[unit_idx..unit_idx + unit_len];
+        if unit_idx == 0 || !["ns", "us", "ms", "s", "m", "h"].contains(&unit) {
+            bail!(
+                "Invalid duration {:?}, expected something like \"24h\" or \"1h30m\"",
+                duration
+            );
+        }
+
+        rest = &rest[unit_idx + unit_len..];
+    }
+
+    Ok(())
+}
+
+/// Treats a missing git config key as `None` and any other error as fatal
+fn git_opt<T>(key: &str, res: Result<T, git2::Error>) -> Result<Option<T>, Error> {
+    match res {
+        Ok(val) => Ok(Some(val)),
+        Err(ref e) if e.code() == ErrorCode::NotFound => Ok(None),
+        Err(e) => bail!("Invalid value for {}: {}", key, e),
+    }
+}
+
+fn git_string(config: &Config, key: &str) -> Result<Option<String>, Error> {
+    git_opt(key, config.get_string(key))
+}
+
+fn git_bool(config: &Config, key: &str) -> Result<Option<bool>, Error> {
+    git_opt(key, config.get_bool(key))
+}
+
+/// Reads a non-negative integer; git's k/m/g suffixes are supported
+fn git_u64(config: &Config, key: &str) -> Result<Option<u64>, Error> {
+    match git_opt(key, config.get_i64(key))? {
+        Some(val) if val < 0 => bail!("{} must not be negative, got {}", key, val),
+        other => Ok(other.map(|val| val as u64)),
+    }
+}
+
+fn to_port(val: u64, key: &str) -> Result<u16, Error> {
+    u16::try_from(val).map_err(|_| format_err!("{} is not a valid port: {}", key, val))
+}
+
+fn to_u32(val: u64, key: &str) -> Result<u32, Error> {
+    u32::try_from(val).map_err(|_| format_err!("{} is too large: {}", key, val))
+}
+
+/// Parses environment variable `var` if it's set
+fn env_parse<T>(var: &str) -> Result<Option<T>, Error>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    match env::var(var) {
+        Ok(val) => val
+            .parse()
+            .map(Some)
+            .map_err(|e| format_err!("Invalid value for {}: {}", var, e)),
+        Err(_) => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::process;
+
+    /// A git config file in the temp dir, deleted on drop
+    struct TempGitConfig {
+        path: PathBuf,
+        config: Config,
+    }
+
+    impl Drop for TempGitConfig {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+
+    /// Writes `contents` to a fresh git config file and opens it
+    fn git_config(name: &str, contents: &str) -> TempGitConfig {
+        let path = env::temp_dir().join(format!("nip-config-{}-{}", name, process::id()));
+        fs::write(&path, contents).unwrap();
+        let config = Config::open(&path).unwrap();
+        TempGitConfig { path, config }
+    }
+
+    #[test]
+    fn later_layers_take_precedence() {
+        let mut config = NipConfig::default();
+        config
+            .apply(&PartialNipConfig {
+                api_port: Some(5002),
+                ipns_key: Some("file-key".to_owned()),
+                ..Default::default()
+            })
+            .unwrap();
+        config
+            .apply(&PartialNipConfig {
+                ipns_key: Some("env-key".to_owned()),
+                ..Default::default()
+            })
+            .unwrap();
+
+        assert_eq!(config.api_port, 5002);
+        assert_eq!(config.ipns_key, Some("env-key".to_owned()));
+        assert_eq!(config.api_host, NipConfig::default().api_host);
+    }
+
+    #[test]
+    fn api_host_and_port_override_api_within_a_layer() {
+        let mut config = NipConfig::default();
+        config
+            .apply(&PartialNipConfig {
+                api: Some("/dns4/example.com/tcp/5001".to_owned()),
+                api_port: Some(5002),
+                ..Default::default()
+            })
+            .unwrap();
+
+        assert_eq!(config.api_host, "example.com");
+        assert_eq!(config.api_port, 5002);
+    }
+
+    #[test]
+    fn remote_settings_override_repo_settings() {
+        let git_config = git_config(
+            "remote",
+            "[nip]\n\
+             \tapiPort = 5002\n\
+             \tipnsKey = repo-key\n\
+             \tpinnedGenerations = 3\n\
+             [remote \"origin\"]\n\
+             \tnip-ipns-key = origin-key\n\
+             \tnip-pinned-generations = 1\n",
+        );
+
+        let mut config = NipConfig::default();
+        config
+            .apply(&PartialNipConfig::from_git_config(&git_config.config).unwrap())
+            .unwrap();
+        config
+            .apply(&PartialNipConfig::from_git_remote_config(&git_config.config, "origin").unwrap())
+            .unwrap();
+
+        assert_eq!(config.api_port, 5002);
+        assert_eq!(config.ipns_key, Some("origin-key".to_owned()));
+        assert_eq!(config.pinned_generations, Some(1));
+    }
+
+    #[test]
+    fn out_of_range_counts_are_rejected() {
+        let git_config = git_config("range", "[nip]\n\tpinnedGenerations = 5000000000\n");
+        assert!(PartialNipConfig::from_git_config(&git_config.config).is_err());
+    }
+
+    #[test]
+    fn invalid_values_are_rejected() {
+        let layers = [
+            PartialNipConfig {
+                signature_policy: Some("sometimes".to_owned()),
+                ..Default::default()
+            },
+            PartialNipConfig {
+                ipns_lifetime: Some("a while".to_owned()),
+                ..Default::default()
+            },
+        ];
+
+        for layer in &layers {
+            assert!(NipConfig::default().apply(layer).is_err());
+        }
+    }
+
+    #[test]
+    fn format_size_picks_a_binary_unit() {
+        assert_eq!(format_size(0), "0 B");
+        assert_eq!(format_size(1023), "1023 B");
+        assert_eq!(format_size(1024), "1.0 KiB");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(500 * 1024 * 1024), "500.0 MiB");
+        assert_eq!(format_size(3 * 1024 * 1024 * 1024 * 1024 * 1024), "3072.0 TiB");
+    }
+
+    #[test]
+    fn parse_api_endpoint_accepts_host_port_pairs() {
+        assert_eq!(
+            parse_api_endpoint("localhost:5001").unwrap(),
+            ("localhost".to_owned(), 5001)
+        );
+        assert_eq!(
+            parse_api_endpoint("[::1]:5001").unwrap(),
+            ("[::1]".to_owned(), 5001)
+        );
+    }
+
+    #[test]
+    fn parse_api_endpoint_accepts_multiaddrs() {
+        assert_eq!(
+            parse_api_endpoint("/ip4/127.0.0.1/tcp/5001").unwrap(),
+            ("127.0.0.1".to_owned(), 5001)
+        );
+        assert_eq!(
+            parse_api_endpoint("/dns4/example.com/tcp/5001/http").unwrap(),
+            ("example.com".to_owned(), 5001)
+        );
+        assert_eq!(
+            parse_api_endpoint("/ip6/::1/tcp/5001").unwrap(),
+            ("[::1]".to_owned(), 5001)
+        );
+    }
+
+    #[test]
+    fn parse_api_endpoint_rejects_unusable_endpoints() {
+        for endpoint in &[
+            "localhost",
+            "localhost:api",
+            "localhost:70000",
+            "/ip4/127.0.0.1",
+            "/ip4/127.0.0.1/tcp",
+            "/ip4/127.0.0.1/udp/5001",
+            "/unix/tmp/ipfs.sock",
+            "/dns4/example.com/tcp/443/https",
+            "/ip4/127.0.0.1/tcp/5001/http/extra",
+        ] {
+            assert!(
+                parse_api_endpoint(endpoint).is_err(),
+                "{} should be rejected",
+                endpoint
+            );
+        }
+    }
+}
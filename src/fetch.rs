@@ -0,0 +1,91 @@
+//! Downloading nip indices and objects from IPFS
+use failure::Error;
+use ipfs_api::IpfsClient;
+use tokio::{
+    prelude::{FutureExt, Stream},
+    runtime::current_thread,
+};
+use nip_core::{
+    ipfs_cat, migrate_index, migrate_object, parse_nip_header, NIPIndex, NIPObject, NIPRemote,
+    NIP_HEADER_LEN, NIP_PROTOCOL_VERSION,
+};
+
+use std::time::Duration;
+
+use crate::{trace, IpnsCache};
+
+/// The placeholder nip uses instead of an IPFS hash for submodule tips in `NIPIndex.objects`
+pub static NIP_SUBMODULE_TIP: &str = "submodule-tip";
+
+/// Downloads the index at `ipfs_hash` and migrates it to the current protocol version.
+pub fn fetch_index(ipfs_hash: &str, ipfs: &mut IpfsClient) -> Result<NIPIndex, Error> {
+    let idx_bytes = trace::ipfs(&format!("cat {}", ipfs_hash), ipfs_cat(ipfs_hash, ipfs))?;
+    migrate_index_bytes(&idx_bytes, ipfs_hash, ipfs)
+}
+
+/// Like `fetch_index`, but gives up if the index doesn't arrive within `timeout`, e.g. because no
+/// reachable node has it anymore.
+pub fn fetch_index_within(
+    ipfs_hash: &str,
+    timeout: Duration,
+    ipfs: &mut IpfsClient,
+) -> Result<NIPIndex, Error> {
+    let res = current_thread::block_on_all(ipfs.cat(ipfs_hash).concat2().timeout(timeout));
+    let idx_bytes = trace::ipfs(&format!("cat {}", ipfs_hash), res).map_err(|e| {
+        if e.is_elapsed() {
+            format_err!("Fetching {} timed out after {:?}", ipfs_hash, timeout)
+        } else {
+            format_err!("Could not fetch {}: {}", ipfs_hash, e)
+        }
+    })?;
+    migrate_index_bytes(&idx_bytes, ipfs_hash, ipfs)
+}
+
+fn migrate_index_bytes(
+    idx_bytes: &[u8],
+    ipfs_hash: &str,
+    ipfs: &mut IpfsClient,
+) -> Result<NIPIndex, Error> {
+    let version = parse_nip_header(idx_bytes)?;
+
+    if version < NIP_PROTOCOL_VERSION {
+        info!(
+            "Migrating {}: version {} -> {}",
+            ipfs_hash, version, NIP_PROTOCOL_VERSION
+        );
+    }
+    migrate_index(&idx_bytes[NIP_HEADER_LEN..], version, ipfs)
+}
+
+/// Downloads the object for `git_hash` stored at `ipfs_hash` and migrates it to the current
+/// protocol version.
+pub fn fetch_object(
+    ipfs_hash: &str,
+    git_hash: &str,
+    ipfs: &mut IpfsClient,
+) -> Result<NIPObject, Error> {
+    let obj_bytes = trace::ipfs(&format!("cat {}", ipfs_hash), ipfs_cat(ipfs_hash, ipfs))?;
+    let version = parse_nip_header(obj_bytes.as_slice())?;
+
+    migrate_object(&obj_bytes[NIP_HEADER_LEN..], git_hash, version)
+}
+
+/// Returns the `/ipfs/` path `nip_remote` currently points at, resolving IPNS names if necessary.
+pub fn resolve_to_ipfs_path(
+    nip_remote: &NIPRemote,
+    ipns_cache: &mut IpnsCache,
+    ipfs: &mut IpfsClient,
+) -> Result<String, Error> {
+    match nip_remote {
+        NIPRemote::ExistingIPFS(_) => Ok(nip_remote
+            .get_hash()
+            .ok_or_else(|| format_err!("{} has no hash", nip_remote.to_string()))?),
+        NIPRemote::ExistingIPNS(_) => {
+            let name = nip_remote
+                .get_hash()
+                .ok_or_else(|| format_err!("{} has no hash", nip_remote.to_string()))?;
+            ipns_cache.resolve(&name, ipfs)
+        }
+        new => bail!("{} is not an existing nip repo", new.to_string()),
+    }
+}
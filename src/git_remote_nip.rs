@@ -8,18 +8,30 @@ extern crate serde_derive;
 use colored::*;
 use docopt::Docopt;
 use failure::Error;
-use git2::Repository;
+use git2::{Config, ObjectType, Oid, Repository, Signature, TreeWalkMode, TreeWalkResult};
 use ipfs_api::IpfsClient;
 use log::LevelFilter;
 use tokio::runtime::current_thread;
 
 use std::{
+    collections::{BTreeSet, HashSet},
     env,
+    fs::{self, OpenOptions},
     io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
     process,
+    time::Duration,
 };
 
-use nip_core::{ipfs_cat, migrate_index, parse_nip_header, NIPIndex, NIPRemote, NIP_HEADER_LEN};
+use nip::{
+    config::format_size,
+    fetch::{fetch_index, fetch_index_within, fetch_object, resolve_to_ipfs_path},
+    logging, session,
+    trace::{self, TracedReader, TracedWriter},
+    IndexObservation, IpnsCache, NipConfig, NipState, RemoteState, SessionLock, SignaturePolicy,
+    UrlUpdatePolicy,
+};
+use nip_core::{ipfs_cat, NIPIndex, NIPObjectMetadata, NIPRemote};
 
 static USAGE: &'static str = "
 nip - the IPFS git remote helper that puts your repo objects Nowhere In Particular.
@@ -29,6 +41,15 @@ Usage: git-remote-nip <remote> <mode-or-hash>
        git-remote-nip --version
 ";
 
+/// The notes ref `nip.pushNotes` records published indices in
+static NIP_NOTES_REF: &str = "refs/notes/nip";
+
+/// Where fetched history waits for signature verification, followed by the fetched git hash
+static NIP_STAGING_REF_PREFIX: &str = "refs/nip/fetch/";
+
+/// How many annotated tags deep `list` follows tags of tags
+static MAX_TAG_DEPTH: usize = 16;
+
 /// NIP's remote helper API capabilities
 static NIP_CAPS: &[&'static str] = &["fetch", "push"];
 
@@ -38,9 +59,82 @@ struct NIPArgs {
     arg_mode_or_hash: String,
 }
 
-fn main() {
-    nip_core::init_logging(LevelFilter::Info);
+/// What a helper session knows about the remote it works with
+struct Session<'a> {
+    /// The remote's name in the local repo
+    remote_name: &'a str,
+    nip_remote: &'a NIPRemote,
+    config: &'a NipConfig,
+    ipns_cache: IpnsCache,
+    /// The `/ipfs/` path of the index the session started from; `None` for new remotes
+    index_path: Option<String>,
+    /// Set when using the last known good index, which must not be pushed on top of
+    read_only: bool,
+    /// The index combined with the `remote.<name>.nip-union-index` indices, which fetches use
+    /// instead of the remote's own; pushes only ever build on the latter
+    union_idx: Option<NIPIndex>,
+}
+
+/// What a push published, written to `nip.pushReceipt` for release pipelines to keep
+#[derive(Debug, Serialize)]
+struct PushReceipt {
+    remote: String,
+    /// The `/ipfs/` path of the new index
+    index: String,
+    /// The `/ipfs/` path of the index the push started from; `None` for new remotes
+    previous_index: Option<String>,
+    refs: Vec<ReceiptRef>,
+    /// How many objects were uploaded
+    objects: usize,
+    /// How big the uploaded objects are uncompressed, as far as the local repo knows
+    bytes: u64,
+    /// Seconds since the Unix epoch
+    pushed_at: u64,
+}
+
+/// A ref changed by a push; `old` is `None` for new refs and `new` is `None` for deleted ones
+#[derive(Debug, Serialize)]
+struct ReceiptRef {
+    name: String,
+    old: Option<String>,
+    new: Option<String>,
+}
+
+/// Failures that are reported to the user as-is, without a backtrace
+#[derive(Debug, Fail)]
+enum HelperError {
+    #[fail(display = "Could not load nip configuration: {}", _0)]
+    Config(String),
+    #[fail(display = "Invalid nip URL {:?}: {}", _0, _1)]
+    InvalidUrl(String, String),
+    #[fail(
+        display = "Could not connect to IPFS at {}, are you sure `ipfs daemon` is running?",
+        _0
+    )]
+    IpfsUnreachable(String),
+    #[fail(display = "Could not get the nip index at {}: {}", _0, _1)]
+    IndexUnavailable(String, String),
+    #[fail(display = "{}", _0)]
+    Repo(String),
+    #[fail(display = "{}", _0)]
+    Locked(String),
+}
+
+impl HelperError {
+    /// Exit codes follow sysexits(3)
+    fn exit_code(&self) -> i32 {
+        match self {
+            HelperError::InvalidUrl(..) => 64, // EX_USAGE
+            HelperError::Repo(_) => 66, // EX_NOINPUT
+            HelperError::IpfsUnreachable(_) => 69, // EX_UNAVAILABLE
+            HelperError::IndexUnavailable(..) => 69, // EX_UNAVAILABLE
+            HelperError::Locked(_) => 75, // EX_TEMPFAIL
+            HelperError::Config(_) => 78, // EX_CONFIG
+        }
+    }
+}
 
+fn main() {
     let args: NIPArgs = Docopt::new(USAGE)
         .and_then(|d| {
             d.help(true)
@@ -50,65 +144,436 @@ fn main() {
         })
         .unwrap_or_else(|e| e.exit());
 
+    if let Err(e) = run(&args) {
+        // git shows our stderr verbatim, keep it to one line unless debugging
+        match e.downcast_ref::<HelperError>() {
+            Some(helper_err) => {
+                error!("{}", helper_err);
+                process::exit(helper_err.exit_code());
+            }
+            None => {
+                error!("{}", e);
+                debug!("{:?}", e);
+                process::exit(1);
+            }
+        }
+    }
+}
+
+/// The body of a helper session; everything it owns (e.g. the session lock) is released by the
+/// time it returns.
+fn run(args: &NIPArgs) -> Result<(), Error> {
+    // The repo is only required once we get past listing (e.g. `git ls-remote` works without
+    // one), but its git config takes part in configuring nip right away.
+    let repo_res = open_repo();
+    let git_config = match repo_res {
+        Ok(ref repo) => repo.config().ok(),
+        Err(_) => Config::open_default().ok(),
+    };
+
+    let config = match NipConfig::load(git_config.as_ref(), Some(&args.arg_remote)) {
+        Ok(config) => {
+            init_logging(&config, repo_res.as_ref().ok());
+            config
+        }
+        Err(e) => {
+            nip_core::init_logging(LevelFilter::Info);
+            return Err(HelperError::Config(e.to_string()).into());
+        }
+    };
+
     trace!("Args: {:#?}", args);
+    debug!("Using config {:#?}", config);
 
-    let nip_remote: NIPRemote = args.arg_mode_or_hash.parse().unwrap();
+    // Keep concurrent git operations in the same repo from interleaving
+    let _session_lock = match repo_res {
+        Ok(ref repo) => {
+            Some(SessionLock::acquire(repo).map_err(|e| HelperError::Locked(e.to_string()))?)
+        }
+        Err(_) => None,
+    };
+
+    let nip_remote: NIPRemote = args.arg_mode_or_hash.parse().map_err(|e: Error| {
+        HelperError::InvalidUrl(args.arg_mode_or_hash.clone(), e.to_string())
+    })?;
+
+    if let Ok(ref repo) = repo_res {
+        warn_about_pending_url(repo, &args.arg_remote, &args.arg_mode_or_hash);
+    }
 
-    let mut ipfs = IpfsClient::new("localhost", 5001).unwrap_or_else(|e| {
-        error!("Could not reach local IPFS instance: {}", e);
-        process::exit(1);
-    });
+    let mut ipfs = config
+        .ipfs_client()
+        .map_err(|e| HelperError::Config(e.to_string()))?;
 
-    let stats = current_thread::block_on_all(ipfs.stats_repo())
+    let stats = trace::ipfs("stats/repo", current_thread::block_on_all(ipfs.stats_repo()))
         .map_err(|e| {
-            error!("Could not connect to IPFS, are you sure `ipfs daemon` is running?");
             debug!("Raw error: {}", e);
-            process::exit(1);
-        })
-        .unwrap();
+            HelperError::IpfsUnreachable(format!("{}:{}", config.api_host, config.api_port))
+        })?;
 
     debug!("IPFS connectivity OK. Datastore stats:\n{:#?}", stats);
 
-    let mut idx = if let Some(ipfs_hash) = nip_remote.get_hash() {
-        let idx_bytes = ipfs_cat(&ipfs_hash, &mut ipfs).unwrap_or_else(|e| {
-            error!("Could not fetch index: {}", e);
-            process::exit(1);
-        });
+    let mut ipns_cache = match repo_res {
+        Ok(ref repo) => IpnsCache::load(repo, &config),
+        Err(_) => IpnsCache::in_memory(&config),
+    };
 
-        let version = parse_nip_header(idx_bytes.as_slice()).unwrap();
+    let mut read_only = false;
+    let mut index_path = None;
 
-        match migrate_index(&idx_bytes[NIP_HEADER_LEN..], version, &mut ipfs) {
-            Ok(idx) => idx,
+    let mut idx = if let Some(ipfs_hash) = nip_remote.get_hash() {
+        let ipfs_path = match resolve_to_ipfs_path(&nip_remote, &mut ipns_cache, &mut ipfs) {
+            Ok(ipfs_path) => ipfs_path,
             Err(e) => {
-                error!("Could not parse index: {}", e.to_string());
-                process::exit(1);
+                let repo = repo_res.as_ref().ok();
+                let fallback = first_available_mirror(&args.arg_remote, &e, repo, &mut ipfs)
+                    .or_else(|| offer_last_good_index(&args.arg_remote, &e, repo));
+                match fallback {
+                    Some(fallback) => {
+                        read_only = true;
+                        fallback
+                    }
+                    None => {
+                        return Err(HelperError::IndexUnavailable(ipfs_hash, e.to_string()).into())
+                    }
+                }
             }
+        };
+
+        let idx = fetch_index(&ipfs_path, &mut ipfs)
+            .map_err(|e| HelperError::IndexUnavailable(ipfs_hash.clone(), e.to_string()))?;
+
+        if let (NIPRemote::ExistingIPNS(_), Ok(repo), false) = (&nip_remote, &repo_res, read_only) {
+            record_remote_state(repo, &args.arg_remote, |state| {
+                state.last_good_index = Some(ipfs_path.clone());
+            });
         }
+
+        index_path = Some(ipfs_path);
+        idx
     } else {
         debug!("Creating a fresh index");
-        NIPIndex::from_nip_remote(&nip_remote, &mut ipfs).unwrap()
+        NIPIndex::from_nip_remote(&nip_remote, &mut ipfs)?
     };
 
     trace!("Using index {:#?}", idx);
 
-    let mut input_handle = BufReader::new(io::stdin());
-    let mut output_handle = io::stdout();
+    let union_idx = match (&repo_res, index_path.is_some()) {
+        (Ok(repo), true) => {
+            union_with_extra_indices(&idx, repo, &args.arg_remote, &mut ipns_cache, &mut ipfs)
+        }
+        _ => None,
+    };
 
-    handle_capabilities(&mut input_handle, &mut output_handle).unwrap();
-    handle_list(&mut input_handle, &mut output_handle, &nip_remote, &idx).unwrap();
+    let mut input_handle = TracedReader::new(BufReader::new(io::stdin()));
+    let mut output_handle = TracedWriter::new(io::stdout());
 
-    let mut repo = Repository::open_from_env().unwrap();
+    handle_capabilities(&mut input_handle, &mut output_handle)?;
+    let listed_idx = union_idx.as_ref().unwrap_or(&idx);
+    let listed = handle_list(
+        &mut input_handle,
+        &mut output_handle,
+        &nip_remote,
+        listed_idx,
+        repo_res.as_ref().ok(),
+        &mut ipfs,
+    )?;
+    if !listed {
+        return Ok(());
+    }
+
+    let mut repo = repo_res.map_err(|e| HelperError::Repo(e.to_string()))?;
+
+    check_alternates(&repo);
+
+    let mut session = Session {
+        remote_name: &args.arg_remote,
+        nip_remote: &nip_remote,
+        config: &config,
+        ipns_cache,
+        index_path,
+        read_only,
+        union_idx,
+    };
 
     handle_fetches_and_pushes(
         &mut input_handle,
         &mut output_handle,
         &mut repo,
-        &nip_remote,
-        &args.arg_remote,
+        &mut session,
         &mut ipfs,
         &mut idx,
     )
-    .unwrap();
+}
+
+/// Sets up logging to stderr, additionally tracing the session to a file if `nip.sessionLog` is
+/// enabled and there's a repo to keep it in.
+fn init_logging(config: &NipConfig, repo: Option<&Repository>) {
+    let repo = match repo {
+        Some(repo) if config.session_log => repo,
+        _ => return nip_core::init_logging(config.log_level),
+    };
+
+    match logging::init_session_logging(repo, config.log_level) {
+        Ok(path) => debug!("Logging this session to {}", path.display()),
+        Err(e) => {
+            nip_core::init_logging(config.log_level);
+            warn!("Could not set up the session log: {}", e);
+        }
+    }
+}
+
+/// Combines `idx` with the indices listed in `remote.<remote_name>.nip-union-index`, so that
+/// commits only some of them have can be fetched. Refs are taken from `idx` first and then from
+/// the other indices in order; indices that can't be obtained are skipped. Returns `None` if there
+/// are no other indices to combine with.
+fn union_with_extra_indices(
+    idx: &NIPIndex,
+    repo: &Repository,
+    remote_name: &str,
+    ipns_cache: &mut IpnsCache,
+    ipfs: &mut IpfsClient,
+) -> Option<NIPIndex> {
+    let urls = repo
+        .config()
+        .map_err(Error::from)
+        .and_then(|git_config| remote_multivar(&git_config, remote_name, "nip-union-index"))
+        .unwrap_or_else(|e| {
+            warn!("Could not read the union indices of {}: {}", remote_name, e);
+            Vec::new()
+        });
+    if urls.is_empty() {
+        return None;
+    }
+
+    let mut union_idx = idx.clone();
+    for url in urls {
+        let res = url
+            .trim_start_matches("nip::")
+            .parse::<NIPRemote>()
+            .and_then(|other_remote| resolve_to_ipfs_path(&other_remote, ipns_cache, ipfs))
+            .and_then(|other_path| fetch_index(&other_path, ipfs));
+
+        let other_idx = match res {
+            Ok(other_idx) => other_idx,
+            Err(e) => {
+                warn!("Skipping union index {} of {}: {}", url, remote_name, e);
+                continue;
+            }
+        };
+
+        for (name, git_hash) in other_idx.refs {
+            match union_idx.refs.get(&name) {
+                Some(existing) if *existing != git_hash => {
+                    debug!("{}: keeping {} over {} from {}", name, existing, git_hash, url)
+                }
+                Some(_) => {}
+                None => {
+                    union_idx.refs.insert(name, git_hash);
+                }
+            }
+        }
+        for (git_hash, ipfs_hash) in other_idx.objects {
+            union_idx.objects.entry(git_hash).or_insert(ipfs_hash);
+        }
+        debug!("Combined {} with union index {}", remote_name, url);
+    }
+
+    Some(union_idx)
+}
+
+/// Looks for the first of the `remote.<remote_name>.nip-mirror-url` indices that can be
+/// downloaded now that resolving the remote failed with `error`, returning its `/ipfs/` path.
+fn first_available_mirror(
+    remote_name: &str,
+    error: &Error,
+    repo: Option<&Repository>,
+    ipfs: &mut IpfsClient,
+) -> Option<String> {
+    let git_config = repo?.config().ok()?;
+    let mirror_urls = remote_multivar(&git_config, remote_name, "nip-mirror-url")
+        .unwrap_or_else(|e| {
+            warn!("Could not read the mirror URLs of {}: {}", remote_name, e);
+            Vec::new()
+        });
+
+    for mirror_url in mirror_urls {
+        let mirror_path = mirror_url.splitn(2, "::").last().unwrap_or("").to_owned();
+        if !mirror_path.starts_with("/ipfs/") {
+            warn!(
+                "Skipping mirror URL {} of {}, it's not an /ipfs/ path",
+                mirror_url, remote_name
+            );
+            continue;
+        }
+
+        match fetch_index(&mirror_path, ipfs) {
+            Ok(_) => {
+                warn!("{}", error);
+                warn!(
+                    "Using mirror {} for {}, pushes will be rejected",
+                    mirror_path, remote_name
+                );
+                return Some(mirror_path);
+            }
+            Err(e) => debug!("Mirror {} is unavailable: {}", mirror_path, e),
+        }
+    }
+
+    None
+}
+
+/// Asks whether to fall back to the last index obtained through `remote_name` now that resolving
+/// it failed with `error`. Returns the index's `/ipfs/` path if the user agrees.
+fn offer_last_good_index(
+    remote_name: &str,
+    error: &Error,
+    repo: Option<&Repository>,
+) -> Option<String> {
+    let last_good = repo
+        .and_then(|repo| NipState::load(repo).ok())
+        .and_then(|mut state| state.remote_mut(remote_name).last_good_index.take())?;
+
+    warn!("{}", error);
+
+    let question = format!(
+        "Use {}, the last known good index of {}, instead (read-only)?",
+        last_good, remote_name
+    );
+    match confirm_on_tty(&question) {
+        Ok(true) => {
+            warn!("Using {} for {}, pushes will be rejected", last_good, remote_name);
+            Some(last_good)
+        }
+        Ok(false) => None,
+        Err(e) => {
+            debug!("Could not ask for confirmation: {}", e);
+            info!(
+                "The last known good index of {} is {}, run `git fetch nip::{}` to fetch from it",
+                remote_name, last_good, last_good
+            );
+            None
+        }
+    }
+}
+
+/// Points out that the last push to `remote_name` left its URL behind (see `nip.updateRemoteUrl`)
+/// if `mode_or_hash` isn't the pushed index either.
+fn warn_about_pending_url(repo: &Repository, remote_name: &str, mode_or_hash: &str) {
+    let pending_url = match NipState::load(repo) {
+        Ok(mut state) => state.remote_mut(remote_name).pending_url.take(),
+        Err(e) => {
+            debug!("Could not load the recorded state: {}", e);
+            return;
+        }
+    };
+
+    if let Some(pending_url) = pending_url {
+        if !pending_url.ends_with(&format!("::{}", mode_or_hash)) {
+            warn!(
+                "The last push to {} published {}, which its URL doesn't point at",
+                remote_name, pending_url
+            );
+        }
+    }
+}
+
+/// Applies `update` to what's recorded about `remote_name`. The state is only ever used for
+/// hints and fallbacks, so failing to record it is not an error.
+fn record_remote_state<F>(repo: &Repository, remote_name: &str, update: F)
+where
+    F: FnOnce(&mut RemoteState),
+{
+    if let Err(e) = NipState::update(repo, remote_name, update) {
+        warn!("Could not update the recorded state of {}: {}", remote_name, e);
+    }
+}
+
+/// Opens the local repository git is running us for. git normally passes it via `GIT_DIR`, which
+/// may point at a bare repo or a linked worktree's private directory; `GIT_COMMON_DIR` and plain
+/// discovery from the working directory are used as fallbacks in that order.
+fn open_repo() -> Result<Repository, Error> {
+    let env_err = match Repository::open_from_env() {
+        Ok(repo) => {
+            debug!("Opened repo at {}", repo.path().display());
+            return Ok(repo);
+        }
+        Err(e) => e,
+    };
+    debug!("Could not open repo from environment: {}", env_err);
+
+    let git_dir = env::var_os("GIT_DIR").map(PathBuf::from);
+    let common_dir = env::var_os("GIT_COMMON_DIR").map(PathBuf::from);
+
+    // A worktree's private git dir only works when it links back to its common dir; if git told
+    // us the common dir explicitly, the refs and objects we care about live there.
+    let candidates = git_dir
+        .iter()
+        .chain(common_dir.iter())
+        .map(|path| path.as_path());
+
+    for candidate in candidates {
+        match Repository::open(candidate) {
+            Ok(repo) => {
+                debug!("Opened repo at {}", repo.path().display());
+                return Ok(repo);
+            }
+            Err(e) => debug!("Could not open {}: {}", candidate.display(), e),
+        }
+    }
+
+    let cwd = env::current_dir()?;
+    Repository::discover(&cwd).map_err(|e| {
+        format_err!(
+            "Could not find the local git repository (GIT_DIR={}, GIT_COMMON_DIR={}, working directory {}): {}",
+            display_opt_path(git_dir.as_ref().map(|p| p.as_path())),
+            display_opt_path(common_dir.as_ref().map(|p| p.as_path())),
+            cwd.display(),
+            e
+        )
+    })
+}
+
+/// Verifies that the repo's object alternates (e.g. from `git clone --reference`) are reachable.
+/// libgit2 consults them for every presence check and read, but silently skips entries it can't
+/// open, which makes shared objects look missing and get fetched all over again.
+fn check_alternates(repo: &Repository) {
+    // Linked worktrees keep their objects in the common dir
+    let objects_dir = env::var_os("GIT_OBJECT_DIRECTORY")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| session::common_dir(repo).join("objects"));
+
+    let mut alternates: Vec<PathBuf> = Vec::new();
+
+    if let Ok(contents) = fs::read_to_string(objects_dir.join("info").join("alternates")) {
+        alternates.extend(
+            contents
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(|line| objects_dir.join(line)),
+        );
+    }
+
+    if let Some(env_alternates) = env::var_os("GIT_ALTERNATE_OBJECT_DIRECTORIES") {
+        alternates.extend(env::split_paths(&env_alternates));
+    }
+
+    for alternate in alternates {
+        if alternate.is_dir() {
+            debug!("Using object alternate {}", alternate.display());
+        } else {
+            warn!(
+                "Object alternate {} is not a directory, objects stored there will be treated as missing",
+                alternate.display()
+            );
+        }
+    }
+}
+
+/// Displays an optional path for diagnostics
+fn display_opt_path(path: Option<&Path>) -> String {
+    path.map_or_else(|| "<unset>".to_owned(), |p| p.display().to_string())
 }
 
 fn handle_capabilities(input_handle: &mut BufRead, output_handle: &mut Write) -> Result<(), Error> {
@@ -128,12 +593,15 @@ fn handle_capabilities(input_handle: &mut BufRead, output_handle: &mut Write) ->
     Ok(())
 }
 
+/// Handles the "list" command; returns `false` if git finished without asking for anything.
 fn handle_list(
     input_handle: &mut BufRead,
     output_handle: &mut Write,
     nip_remote: &NIPRemote,
     idx: &NIPIndex,
-) -> Result<(), Error> {
+    repo: Option<&Repository>,
+    ipfs: &mut IpfsClient,
+) -> Result<bool, Error> {
     let mut line_buf = String::new();
     input_handle.read_line(&mut line_buf)?;
 
@@ -145,7 +613,7 @@ fn handle_list(
         // Sometimes git needs to finish early, e.g. when the local ref doesn't even exist locally
         "\n" => {
             debug!("Git finished early, exiting...");
-            process::exit(0);
+            return Ok(false);
         }
         other => {
             let msg = format!("Expected a \"list*\" command, got {:?}", other);
@@ -165,29 +633,150 @@ fn handle_list(
                 "Listing refs from existing repo at {}",
                 existing.to_string()
             );
+            let namespace = namespace_prefix();
             for (name, git_hash) in &idx.refs {
+                // Within a namespace, only its refs exist, under their names relative to it
+                let name = match (namespace.is_empty(), name.starts_with(&namespace)) {
+                    (true, _) => name.as_str(),
+                    (false, true) => &name[namespace.len()..],
+                    (false, false) => continue,
+                };
+
                 let output = format!("{} {}", git_hash, name);
                 debug!("{}", output);
                 writeln!(output_handle, "{}", output)?;
+
+                // Advertise what annotated tags point at like native transports do
+                if name.starts_with("refs/tags/") {
+                    match peel_tag(git_hash, idx, repo, ipfs) {
+                        Ok(Some(peeled)) => writeln!(output_handle, "{} {}^{{}}", peeled, name)?,
+                        Ok(None) => {}
+                        Err(e) => debug!("Could not peel {}: {}", name, e),
+                    }
+                }
             }
 
             // Indicate that we're done listing
             writeln!(output_handle)?;
         }
     }
+    Ok(true)
+}
+
+/// Returns the prefix the refs of the `GIT_NAMESPACE` git works in have in the index, e.g.
+/// `refs/namespaces/a/refs/namespaces/b/` for `a/b`; outside of namespaces it's empty.
+fn namespace_prefix() -> String {
+    env::var("GIT_NAMESPACE")
+        .unwrap_or_default()
+        .split('/')
+        .filter(|component| !component.is_empty())
+        .map(|component| format!("refs/namespaces/{}/", component))
+        .collect()
+}
+
+/// Makes sure the object a fetch of `ref_name` asked for ended up in the local repo. git itself
+/// points the destination of the user's refspec at it once the fetch is over, which only works
+/// for tags if the tag object itself is present, not just what it tags.
+fn check_fetched_object(git_hash: &str, ref_name: &str, repo: &Repository) -> Result<(), Error> {
+    let oid = Oid::from_str(git_hash)?;
+    if !repo.odb()?.exists(oid) {
+        bail!("{} was fetched for {} but is missing locally", git_hash, ref_name);
+    }
     Ok(())
 }
 
+/// Returns the first non-tag object the tag `git_hash` leads to, or `None` if `git_hash` isn't an
+/// annotated tag. Tags the local repo has are peeled locally, others from their raw data in IPFS.
+fn peel_tag(
+    git_hash: &str,
+    idx: &NIPIndex,
+    repo: Option<&Repository>,
+    ipfs: &mut IpfsClient,
+) -> Result<Option<String>, Error> {
+    let mut current = git_hash.to_owned();
+
+    // Tags of tags are rare, but git allows them
+    for _ in 0..MAX_TAG_DEPTH {
+        let local_target = repo
+            .and_then(|repo| repo.find_tag(Oid::from_str(&current).ok()?).ok())
+            .map(|tag| (tag.target_id().to_string(), tag.target_type()));
+
+        let (target, is_tag) = match local_target {
+            Some((target, kind)) => (target, kind == Some(ObjectType::Tag)),
+            None => match read_remote_tag(&current, idx, ipfs)? {
+                Some(target) => target,
+                None if current == git_hash => return Ok(None),
+                None => bail!("{} is not available as a tag object", current),
+            },
+        };
+
+        if !is_tag {
+            return Ok(Some(target));
+        }
+        current = target;
+    }
+
+    bail!("{} is nested more than {} tags deep", git_hash, MAX_TAG_DEPTH)
+}
+
+/// Reads the target hash of the tag object `git_hash` from `idx` and whether the target is a tag
+/// too. Returns `None` for objects that aren't tags.
+fn read_remote_tag(
+    git_hash: &str,
+    idx: &NIPIndex,
+    ipfs: &mut IpfsClient,
+) -> Result<Option<(String, bool)>, Error> {
+    let obj_ipfs_hash = idx
+        .objects
+        .get(git_hash)
+        .ok_or_else(|| format_err!("{} is not in the index", git_hash))?;
+    let obj = fetch_object(obj_ipfs_hash, git_hash, ipfs)?;
+
+    match obj.metadata {
+        NIPObjectMetadata::Tag { .. } => {}
+        _ => return Ok(None),
+    }
+
+    // The header of a raw tag object starts with "object <hash>" and "type <type>" lines
+    let raw = ipfs_cat(&obj.raw_data_ipfs_hash, ipfs)?;
+    let raw = String::from_utf8_lossy(&raw);
+    let header_value = |key: &str| {
+        raw.lines()
+            .find(|line| line.starts_with(key))
+            .map(|line| line[key.len()..].trim().to_owned())
+    };
+
+    let target = header_value("object ")
+        .ok_or_else(|| format_err!("Tag {} has no target", git_hash))?;
+    let is_tag = header_value("type ").map_or(false, |kind| kind == "tag");
+
+    Ok(Some((target, is_tag)))
+}
+
 fn handle_fetches_and_pushes(
     input_handle: &mut BufRead,
     output_handle: &mut Write,
     repo: &mut Repository,
-    nip_remote: &NIPRemote,
-    remote_name: &str,
+    session: &mut Session,
     ipfs: &mut IpfsClient,
     idx: &mut NIPIndex,
 ) -> Result<(), Error> {
+    let nip_remote = session.nip_remote;
+    let remote_name = session.remote_name;
+    let config = session.config;
+    let ipns_cache = &mut session.ipns_cache;
+
+    let namespace = namespace_prefix();
+
     let mut current_idx = idx.clone();
+    let mut fetched = false;
+
+    // History we had before this session counts as already vetted
+    let known_tips = if config.signature_policy == SignaturePolicy::Ignore {
+        Vec::new()
+    } else {
+        local_commit_tips(repo)?
+    };
 
     for line in input_handle.lines() {
         let line_buf = line?;
@@ -215,7 +804,21 @@ fn handle_fetches_and_pushes(
                 })?;
                 debug!("Parsed ref name: {}", target_ref_name);
 
-                current_idx.fetch_to_ref_from_str(hash_to_fetch, target_ref_name, repo, ipfs)?;
+                // nip_core points the ref it fetches to at the commit, which would expose the
+                // history before its signatures are checked. It gets a staging ref instead; git
+                // updates the refs the user asked for itself once the helper reports success.
+                let staging_ref = format!("{}{}", NIP_STAGING_REF_PREFIX, hash_to_fetch);
+                let fetch_idx = session.union_idx.as_mut().unwrap_or(&mut current_idx);
+                trace::ipfs(
+                    &format!("fetch {} {}", hash_to_fetch, target_ref_name),
+                    fetch_idx.fetch_to_ref_from_str(hash_to_fetch, &staging_ref, repo, ipfs),
+                )?;
+
+                let verified = verify_fetched_signatures(hash_to_fetch, &known_tips, repo, config);
+                remove_staging_ref(&staging_ref, repo)?;
+                verified?;
+                check_fetched_object(hash_to_fetch, target_ref_name, repo)?;
+                fetched = true;
             }
             // push <refspec>
             push_line if push_line.starts_with("push") => {
@@ -248,8 +851,55 @@ fn handle_fetches_and_pushes(
                 })?;
                 debug!("Parsed dst: {}", dst);
 
+                // The name dst has in the index, which differs from dst within `GIT_NAMESPACE`
+                let idx_dst = format!("{}{}", namespace, dst);
+
+                if session.read_only {
+                    writeln!(
+                        output_handle,
+                        "error {} \"the remote could not be resolved, only fetching from its last known good index is possible\"",
+                        dst
+                    )?;
+                    continue;
+                }
+
+                // An empty source means deletion, e.g. `git push nip :branch` or `push --mirror`
+                if src.is_empty() {
+                    if let Err(e) = check_deletion(&idx_dst, &current_idx, repo, config) {
+                        writeln!(output_handle, "error {} \"{}\"", dst, e)?;
+                        continue;
+                    }
+                    match current_idx.refs.remove(&idx_dst) {
+                        Some(git_hash) => debug!("Deleted {} (was {})", dst, git_hash),
+                        None => debug!("{} not present on the remote, nothing to delete", dst),
+                    }
+                    writeln!(output_handle, "ok {}", dst)?;
+                    continue;
+                }
+
+                // There's no server-side protection against losing history on IPFS
+                if force {
+                    if let Err(e) = check_forced_push(src, &idx_dst, &current_idx, repo, config) {
+                        writeln!(output_handle, "error {} \"{}\"", dst, e)?;
+                        continue;
+                    }
+                }
+
+                // nip_core can't walk past the history a shallow clone lacks
+                if let Err(e) = check_shallow_push(src, &current_idx, repo) {
+                    writeln!(output_handle, "error {} \"{}\"", dst, e)?;
+                    continue;
+                }
+
+                // Protect the user from accidentally publishing enormous artifacts
+                if let Err(e) = check_push_size(src, dst, &current_idx, repo, config) {
+                    writeln!(output_handle, "error {} \"{}\"", dst, e)?;
+                    continue;
+                }
+
                 // Upload the object tree
-                match current_idx.push_ref_from_str(src, dst, force, repo, ipfs) {
+                let res = current_idx.push_ref_from_str(src, &idx_dst, force, repo, ipfs);
+                match trace::ipfs(&format!("push {}:{}", src, idx_dst), res) {
                     Ok(_) => {}
                     Err(e) => {
                         writeln!(output_handle, "error {} \"{}\"", dst, e)?;
@@ -277,6 +927,12 @@ fn handle_fetches_and_pushes(
         }
     }
 
+    if let (true, Some(index_path)) = (fetched, session.index_path.as_ref()) {
+        record_remote_state(repo, remote_name, |state| {
+            state.last_fetch = Some(IndexObservation::now(index_path));
+        });
+    }
+
     // Upload current_idx to IPFS if it differs from the original idx
     match current_idx {
         ref unchanged_idx if unchanged_idx == idx => {
@@ -293,8 +949,40 @@ fn handle_fetches_and_pushes(
             );
         }
         mut changed_idx => {
+            // nip_core can't set record lifetimes, so we publish ourselves if any are configured
+            let publish_key = config.ipns_key.as_deref().or_else(|| {
+                if config.ipns_lifetime.is_some() || config.ipns_ttl.is_some() {
+                    Some("self")
+                } else {
+                    None
+                }
+            });
+
             // Upload the changed index
-            let new_nip_remote = changed_idx.ipfs_add(ipfs, Some(nip_remote))?;
+            let new_nip_remote = match (nip_remote, publish_key) {
+                (NIPRemote::NewIPNS, Some(key)) | (NIPRemote::ExistingIPNS(_), Some(key)) => {
+                    publish_under_key(&mut changed_idx, nip_remote, key, config, ipns_cache, ipfs)?
+                }
+                _ => {
+                    let uploaded = trace::ipfs(
+                        "add index",
+                        changed_idx.ipfs_add(ipfs, Some(nip_remote)),
+                    )?;
+                    // nip_core may have republished the name, any cached resolution is outdated
+                    if let Some(name) = uploaded.get_hash() {
+                        ipns_cache.invalidate(&name);
+                    }
+                    uploaded
+                }
+            };
+
+            // Apply the pin retention policy if one is configured
+            if let Some(keep_count) = config.pinned_generations {
+                let timeout = Duration::from_secs(config.timeout);
+                if let Err(e) = unpin_superseded_indices(&changed_idx, keep_count, timeout, ipfs) {
+                    warn!("Could not unpin superseded indices: {}", e);
+                }
+            }
 
             match &new_nip_remote {
                 NIPRemote::NewIPFS | NIPRemote::NewIPNS => {
@@ -333,9 +1021,87 @@ fn handle_fetches_and_pushes(
                     };
                     debug!("Previous IPFS hash: {}", existing.get_hash().unwrap());
                     debug!("New IPFS hash:      {}", existing.get_hash().unwrap());
-                    info!("{} {}", "URL changed:".yellow(), new_repo_url.green());
 
-                    repo.remote_set_url(remote_name, &new_repo_url)?;
+                    if new_repo_url == current_remote_url {
+                        info!("Current URL: {} (not changed)", current_remote_url);
+                    } else if should_update_remote_url(remote_name, &new_repo_url, config) {
+                        info!("{} {}", "URL changed:".yellow(), new_repo_url.green());
+                        repo.remote_set_url(remote_name, &new_repo_url)?;
+                        record_remote_state(repo, remote_name, |state| {
+                            state.pending_url = None;
+                        });
+                    } else {
+                        info!("{} {}", "New URL (not applied):".yellow(), new_repo_url.green());
+                        info!(
+                            "Run `git remote set-url {} {}` to use it",
+                            remote_name, new_repo_url
+                        );
+                        record_remote_state(repo, remote_name, |state| {
+                            state.pending_url = Some(new_repo_url.clone());
+                        });
+                    }
+
+                    match resolve_to_ipfs_path(existing, ipns_cache, ipfs) {
+                        Ok(new_index_path) => {
+                            record_remote_state(repo, remote_name, |state| {
+                                state.last_push = Some(IndexObservation::now(&new_index_path));
+                            });
+
+                            if let Some(ref receipt_path) = config.push_receipt {
+                                let receipt = PushReceipt::new(
+                                    remote_name,
+                                    new_index_path,
+                                    session.index_path.clone(),
+                                    idx,
+                                    &changed_idx,
+                                    repo,
+                                );
+                                if let Err(e) = receipt.write(receipt_path, repo) {
+                                    error!("Could not write the push receipt: {}", e);
+                                }
+                            }
+
+                            let res = update_mirror_urls(repo, remote_name, &new_index_path);
+                            if let Err(e) = res {
+                                warn!("Could not update the mirror URLs of {}: {}", remote_name, e);
+                            }
+
+                            if config.push_notes {
+                                let res = add_push_notes(
+                                    remote_name,
+                                    &new_index_path,
+                                    idx,
+                                    &changed_idx,
+                                    repo,
+                                );
+                                if let Err(e) = res {
+                                    warn!("Could not record the push in git notes: {}", e);
+                                }
+                            }
+
+                            if let Some(ref mirror_dir) = config.mfs_mirror {
+                                let res =
+                                    update_mfs_mirror(mirror_dir, idx, &changed_idx, repo, ipfs);
+                                if let Err(e) = res {
+                                    warn!("Could not update the MFS mirror {}: {}", mirror_dir, e);
+                                }
+                            }
+                        }
+                        Err(e) => warn!("Could not record the pushed index: {}", e),
+                    }
+
+                    // The refs are updated on the remote itself already, git must hear about it
+                    let res = publish_to_mirror_keys(
+                        repo,
+                        remote_name,
+                        existing,
+                        config,
+                        ipns_cache,
+                        ipfs,
+                    );
+                    if let Err(e) = res {
+                        warn!("Could not update the mirror keys of {}: {}", remote_name, e);
+                    }
                 }
             };
         }
@@ -345,3 +1111,814 @@ fn handle_fetches_and_pushes(
 
     Ok(())
 }
+
+impl PushReceipt {
+    /// Describes the push that turned `prev_idx` into `new_idx`
+    fn new(
+        remote_name: &str,
+        index: String,
+        previous_index: Option<String>,
+        prev_idx: &NIPIndex,
+        new_idx: &NIPIndex,
+        repo: &Repository,
+    ) -> Self {
+        let names: BTreeSet<&String> = prev_idx.refs.keys().chain(new_idx.refs.keys()).collect();
+        let refs = names
+            .into_iter()
+            .filter(|name| prev_idx.refs.get(*name) != new_idx.refs.get(*name))
+            .map(|name| ReceiptRef {
+                name: name.clone(),
+                old: prev_idx.refs.get(name).cloned(),
+                new: new_idx.refs.get(name).cloned(),
+            })
+            .collect();
+
+        let uploaded: Vec<&String> = new_idx
+            .objects
+            .keys()
+            .filter(|git_hash| !prev_idx.objects.contains_key(*git_hash))
+            .collect();
+
+        // Submodule tips and the like aren't in the local odb, they don't add to the upload
+        let bytes = repo.odb().ok().map_or(0, |odb| {
+            uploaded
+                .iter()
+                .filter_map(|git_hash| Oid::from_str(git_hash).ok())
+                .filter_map(|oid| odb.read_header(oid).ok())
+                .map(|(size, _)| size as u64)
+                .sum()
+        });
+
+        Self {
+            remote: remote_name.to_owned(),
+            index,
+            previous_index,
+            refs,
+            objects: uploaded.len(),
+            bytes,
+            pushed_at: session::unix_time(),
+        }
+    }
+
+    /// Writes the receipt to `path`, relative to the working directory of `repo` (or its git dir
+    /// for bare repos)
+    fn write(&self, path: &Path, repo: &Repository) -> Result<(), Error> {
+        let path = repo.workdir().unwrap_or_else(|| repo.path()).join(path);
+        session::write_atomically(&path, &serde_json::to_string_pretty(self)?)?;
+        debug!("Wrote push receipt {}", path.display());
+        Ok(())
+    }
+}
+
+/// Appends `<index_path> <remote_name>` to the `NIP_NOTES_REF` note of every commit the push
+/// from `prev_idx` to `new_idx` pointed a ref at.
+fn add_push_notes(
+    remote_name: &str,
+    index_path: &str,
+    prev_idx: &NIPIndex,
+    new_idx: &NIPIndex,
+    repo: &Repository,
+) -> Result<(), Error> {
+    let signature = repo
+        .signature()
+        .or_else(|_| Signature::now("nip", "nip@localhost"))?;
+
+    let mut commits = BTreeSet::new();
+    for (name, git_hash) in &new_idx.refs {
+        if prev_idx.refs.get(name) == Some(git_hash) {
+            continue;
+        }
+        // Only commits get notes, annotated tags are annotated through what they tag
+        if let Ok(commit) = repo
+            .find_object(Oid::from_str(git_hash)?, None)
+            .and_then(|obj| obj.peel(ObjectType::Commit))
+        {
+            commits.insert(commit.id());
+        }
+    }
+
+    let line = format!("{} {}", index_path, remote_name);
+    for oid in commits {
+        let note = match repo.find_note(Some(NIP_NOTES_REF), oid) {
+            Ok(existing) => format!("{}\n{}", existing.message().unwrap_or("").trim_end(), line),
+            Err(_) => line.clone(),
+        };
+        repo.note(&signature, &signature, Some(NIP_NOTES_REF), oid, &note, true)?;
+        debug!("Noted {} on {}", index_path, oid);
+    }
+
+    Ok(())
+}
+
+/// Brings the branch directories under the MFS directory `mirror_dir` in line with the push from
+/// `prev_idx` to `new_idx`: changed branches get their new tree, deleted ones are removed.
+fn update_mfs_mirror(
+    mirror_dir: &str,
+    prev_idx: &NIPIndex,
+    new_idx: &NIPIndex,
+    repo: &Repository,
+    ipfs: &mut IpfsClient,
+) -> Result<(), Error> {
+    let mirror_dir = mirror_dir.trim_end_matches('/');
+    let branch_dir = |name: &str| {
+        if name.starts_with("refs/heads/") {
+            Some(format!("{}/{}", mirror_dir, &name["refs/heads/".len()..]))
+        } else {
+            None
+        }
+    };
+
+    for name in prev_idx.refs.keys() {
+        if new_idx.refs.contains_key(name) {
+            continue;
+        }
+        if let Some(dir) = branch_dir(name) {
+            trace::ipfs(
+                &format!("files/rm {}", dir),
+                current_thread::block_on_all(ipfs.files_rm(&dir, true)),
+            )
+            .map_err(|e| format_err!("Could not remove {}: {}", dir, e))?;
+            debug!("Removed {} from the MFS mirror", dir);
+        }
+    }
+
+    for (name, git_hash) in &new_idx.refs {
+        if prev_idx.refs.get(name) == Some(git_hash) {
+            continue;
+        }
+        let dir = match branch_dir(name) {
+            Some(dir) => dir,
+            None => continue,
+        };
+
+        let tree_oid = repo
+            .find_object(Oid::from_str(git_hash)?, None)?
+            .peel(ObjectType::Tree)?
+            .id();
+        let hash = nip::mfs::write_tree(repo, &repo.find_tree(tree_oid)?, new_idx, &dir, ipfs)?;
+        info!("Mirrored {} to {} ({})", name, dir, hash);
+    }
+
+    Ok(())
+}
+
+/// Decides whether to point `remote_name` at `new_url` according to `nip.updateRemoteUrl`
+fn should_update_remote_url(remote_name: &str, new_url: &str, config: &NipConfig) -> bool {
+    match config.update_remote_url {
+        UrlUpdatePolicy::Always => true,
+        UrlUpdatePolicy::Never => false,
+        UrlUpdatePolicy::Prompt => {
+            match confirm_on_tty(&format!("Point {} at {}?", remote_name, new_url)) {
+                Ok(answer) => answer,
+                Err(e) => {
+                    debug!("Could not ask for confirmation: {}", e);
+                    false
+                }
+            }
+        }
+    }
+}
+
+/// Uploads `idx` and publishes it under IPNS key `key`, in place of nip_core's default key.
+fn publish_under_key(
+    idx: &mut NIPIndex,
+    nip_remote: &NIPRemote,
+    key: &str,
+    config: &NipConfig,
+    ipns_cache: &mut IpnsCache,
+    ipfs: &mut IpfsClient,
+) -> Result<NIPRemote, Error> {
+    let prev_remote = match nip_remote {
+        NIPRemote::ExistingIPNS(_) => {
+            Some(resolve_to_ipfs_path(nip_remote, ipns_cache, ipfs)?.parse()?)
+        }
+        _ => None,
+    };
+
+    let uploaded = trace::ipfs("add index", idx.ipfs_add(ipfs, prev_remote.as_ref()))?;
+    let ipfs_path = resolve_to_ipfs_path(&uploaded, ipns_cache, ipfs)?;
+
+    let published = trace::ipfs(
+        &format!("name/publish {} (key {})", ipfs_path, key),
+        current_thread::block_on_all(ipfs.name_publish(
+            &ipfs_path,
+            false,
+            config.ipns_lifetime.as_deref(),
+            config.ipns_ttl.as_deref(),
+            Some(key),
+        )),
+    )
+    .map_err(|e| format_err!("Could not publish {} under key {}: {}", ipfs_path, key, e))?;
+    debug!("Published {} at /ipns/{} (key {})", ipfs_path, published.name, key);
+
+    let name = format!("/ipns/{}", published.name);
+    ipns_cache.insert(&name, &ipfs_path);
+
+    name.parse()
+}
+
+/// Publishes the freshly uploaded index under every IPNS key listed in
+/// `remote.<remote_name>.nip-mirror-key`, keeping replicas of the remote in sync. All keys are
+/// attempted before any failure is reported.
+fn publish_to_mirror_keys(
+    repo: &Repository,
+    remote_name: &str,
+    new_nip_remote: &NIPRemote,
+    config: &NipConfig,
+    ipns_cache: &mut IpnsCache,
+    ipfs: &mut IpfsClient,
+) -> Result<(), Error> {
+    let keys = remote_multivar(&repo.config()?, remote_name, "nip-mirror-key")?;
+    if keys.is_empty() {
+        return Ok(());
+    }
+
+    let ipfs_path = resolve_to_ipfs_path(new_nip_remote, ipns_cache, ipfs)?;
+
+    let mut failed_keys = Vec::new();
+    for key in keys {
+        let res = current_thread::block_on_all(ipfs.name_publish(
+            &ipfs_path,
+            false,
+            config.ipns_lifetime.as_deref(),
+            config.ipns_ttl.as_deref(),
+            Some(&key),
+        ));
+        match trace::ipfs(&format!("name/publish {} (key {})", ipfs_path, key), res) {
+            Ok(published) => info!("Mirrored {} at /ipns/{} (key {})", ipfs_path, published.name, key),
+            Err(e) => {
+                error!("Could not publish {} under key {}: {}", ipfs_path, key, e);
+                failed_keys.push(key);
+            }
+        }
+    }
+
+    if !failed_keys.is_empty() {
+        bail!("Mirroring failed for key(s): {}", failed_keys.join(", "));
+    }
+
+    Ok(())
+}
+
+/// Returns all values of the multi-valued `remote.<remote_name>.<var>`, in order
+fn remote_multivar(
+    git_config: &Config,
+    remote_name: &str,
+    var: &str,
+) -> Result<Vec<String>, Error> {
+    let var_regex = format!("^remote\\.{}\\.{}$", remote_name.replace('.', "\\."), var);
+
+    let mut values = Vec::new();
+    for entry in &git_config.entries(Some(&var_regex))? {
+        if let Some(value) = entry?.value() {
+            values.push(value.to_owned());
+        }
+    }
+    Ok(values)
+}
+
+/// Points the `remote.<remote_name>.nip-mirror-url` entries at the `index_path` a push produced.
+/// The entries hold the most recently pushed indices, newest first, so the fresh index is put in
+/// front and the oldest one falls off.
+fn update_mirror_urls(
+    repo: &Repository,
+    remote_name: &str,
+    index_path: &str,
+) -> Result<(), Error> {
+    let old_urls = remote_multivar(&repo.config()?, remote_name, "nip-mirror-url")?;
+    if old_urls.is_empty() {
+        return Ok(());
+    }
+
+    let new_url = format!("nip::{}", index_path);
+    let new_urls: Vec<&str> = std::iter::once(new_url.as_str())
+        .chain(old_urls.iter().map(|url| url.as_str()).filter(|url| *url != new_url))
+        .take(old_urls.len())
+        .collect();
+
+    let key = format!("remote.{}.nip-mirror-url", remote_name);
+    if let Err(e) = set_multivar(repo, &key, &new_urls) {
+        let old_urls: Vec<&str> = old_urls.iter().map(|url| url.as_str()).collect();
+        if let Err(restore_e) = set_multivar(repo, &key, &old_urls) {
+            bail!("{}; restoring the previous mirror URLs failed too: {}", e, restore_e);
+        }
+        return Err(e);
+    }
+    debug!("Mirror URLs of {}: {:?}", remote_name, new_urls);
+
+    Ok(())
+}
+
+/// Replaces all values of the multi-valued `key` with `values`. git2 can't do that beyond a
+/// single value, git itself can; the first value replaces the old ones and the rest is added, so
+/// the key is never left without a value.
+fn set_multivar(repo: &Repository, key: &str, values: &[&str]) -> Result<(), Error> {
+    let (first, rest) = match values.split_first() {
+        Some(split) => split,
+        None => return git_config_cmd(repo, &["--unset-all", key]),
+    };
+
+    git_config_cmd(repo, &["--replace-all", key, first])?;
+    for value in rest {
+        git_config_cmd(repo, &["--add", key, value])?;
+    }
+    Ok(())
+}
+
+/// Runs `git config <args>` on the config of `repo`
+fn git_config_cmd(repo: &Repository, args: &[&str]) -> Result<(), Error> {
+    let status = process::Command::new("git")
+        .arg("--git-dir")
+        .arg(repo.path())
+        .arg("config")
+        .args(args)
+        .status()
+        .map_err(|e| format_err!("Could not run git config: {}", e))?;
+
+    if !status.success() {
+        bail!("git config {} failed ({})", args.join(" "), status);
+    }
+    Ok(())
+}
+
+/// Unpins the index generations that are more than `keep_count` steps behind `idx` from the local
+/// IPFS node. The walk stops at the first generation that is not pinned anymore, as everything
+/// older than that has already been dealt with by a previous push; that generation is never
+/// downloaded, the node may well have garbage-collected it.
+fn unpin_superseded_indices(
+    idx: &NIPIndex,
+    keep_count: u32,
+    timeout: Duration,
+    ipfs: &mut IpfsClient,
+) -> Result<(), Error> {
+    let mut next_hash = idx.prev_idx_hash.clone();
+    let mut generation = 1;
+
+    while let Some(hash) = next_hash {
+        if !is_pinned(&hash, ipfs) {
+            debug!("{} is not pinned anymore, stopping", hash);
+            break;
+        }
+
+        // Find out about the next generation before this one possibly gets unpinned
+        next_hash = fetch_index_within(&hash, timeout, ipfs)?.prev_idx_hash;
+
+        if generation > keep_count {
+            let res = current_thread::block_on_all(ipfs.pin_rm(&hash, true));
+            match trace::ipfs(&format!("pin/rm {}", hash), res) {
+                Ok(_) => debug!("Unpinned superseded index {}", hash),
+                Err(e) => {
+                    debug!("Could not unpin {} ({}), assuming it's done already", hash, e);
+                    break;
+                }
+            }
+        }
+        generation += 1;
+    }
+
+    Ok(())
+}
+
+/// Checks whether the local IPFS node pins `hash` recursively. IPFS answers with an error for
+/// hashes it doesn't pin.
+fn is_pinned(hash: &str, ipfs: &mut IpfsClient) -> bool {
+    let res = current_thread::block_on_all(ipfs.pin_ls(Some(hash), Some("recursive")));
+    match trace::ipfs(&format!("pin/ls {}", hash), res) {
+        Ok(pins) => !pins.keys.is_empty(),
+        Err(e) => {
+            debug!("Treating {} as unpinned: {}", hash, e);
+            false
+        }
+    }
+}
+
+/// Returns the commits all local refs point at
+fn local_commit_tips(repo: &Repository) -> Result<Vec<Oid>, Error> {
+    let mut tips = Vec::new();
+    for reference in repo.references()? {
+        if let Ok(commit) = reference?.peel(ObjectType::Commit) {
+            tips.push(commit.id());
+        }
+    }
+    Ok(tips)
+}
+
+/// Applies the configured signature policy to the tags and commits reachable from `git_hash`
+/// that weren't reachable from `known_tips` before.
+fn verify_fetched_signatures(
+    git_hash: &str,
+    known_tips: &[Oid],
+    repo: &Repository,
+    config: &NipConfig,
+) -> Result<(), Error> {
+    if config.signature_policy == SignaturePolicy::Ignore {
+        return Ok(());
+    }
+
+    let mut unverified = Vec::new();
+
+    // Peel annotated tags one by one, each of them may carry its own signature
+    let mut obj = repo.find_object(Oid::from_str(git_hash)?, None)?;
+    while let Some(tag) = obj.as_tag() {
+        if !verify_signature("verify-tag", tag.id(), repo, config)? {
+            unverified.push(format!("tag {}", tag.id()));
+        }
+        obj = tag.target()?;
+    }
+
+    if obj.kind() == Some(ObjectType::Commit) {
+        let mut revwalk = repo.revwalk()?;
+        revwalk.push(obj.id())?;
+        for tip in known_tips {
+            revwalk.hide(*tip)?;
+        }
+
+        for oid in revwalk {
+            let oid = oid?;
+            if !verify_signature("verify-commit", oid, repo, config)? {
+                unverified.push(format!("commit {}", oid));
+            }
+        }
+    }
+
+    if unverified.is_empty() {
+        debug!("All new history reachable from {} is signed", git_hash);
+        return Ok(());
+    }
+
+    match config.signature_policy {
+        SignaturePolicy::Require => bail!(
+            "Fetched history contains {} object(s) without a trusted signature, the first being {}",
+            unverified.len(),
+            unverified[0]
+        ),
+        _ => {
+            for what in &unverified {
+                warn!("{} has no trusted signature", what);
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Deletes the staging ref a fetch went to, if nip_core created one
+fn remove_staging_ref(staging_ref: &str, repo: &Repository) -> Result<(), Error> {
+    if let Ok(mut reference) = repo.find_reference(staging_ref) {
+        reference.delete()?;
+    }
+    Ok(())
+}
+
+/// Runs `git <subcommand> <oid>` which makes git check the signature with its usual gpg/ssh
+/// settings. Returns whether the signature is good.
+fn verify_signature(
+    subcommand: &str,
+    oid: Oid,
+    repo: &Repository,
+    config: &NipConfig,
+) -> Result<bool, Error> {
+    let mut cmd = process::Command::new("git");
+    cmd.arg("--git-dir")
+        .arg(repo.path())
+        .arg(subcommand)
+        .arg(oid.to_string())
+        .stdout(process::Stdio::null())
+        .stderr(process::Stdio::null());
+
+    if let Some(ref keyring) = config.signature_keyring {
+        cmd.env("GNUPGHOME", keyring);
+    }
+
+    let status = cmd
+        .status()
+        .map_err(|e| format_err!("Could not run git {}: {}", subcommand, e))?;
+    trace!("git {} {}: {}", subcommand, oid, status);
+
+    Ok(status.success())
+}
+
+/// Makes sure that a forced push which would discard commits the remote index records for `dst`
+/// is either confirmed by the user or allowed with `nip.allowForce`.
+fn check_forced_push(
+    src: &str,
+    dst: &str,
+    idx: &NIPIndex,
+    repo: &Repository,
+    config: &NipConfig,
+) -> Result<(), Error> {
+    let old_hash = match idx.refs.get(dst) {
+        Some(old_hash) => old_hash,
+        None => return Ok(()),
+    };
+
+    let new_oid = match repo
+        .revparse_single(src)
+        .and_then(|obj| obj.peel(ObjectType::Commit))
+    {
+        Ok(commit) => commit.id(),
+        Err(e) => {
+            debug!("{} doesn't resolve to a commit ({}), not checking history", src, e);
+            return Ok(());
+        }
+    };
+
+    // The remote ref may point at an annotated tag
+    let old_oid = Oid::from_str(old_hash)?;
+    let old_commit_oid = repo
+        .find_object(old_oid, None)
+        .and_then(|obj| obj.peel(ObjectType::Commit))
+        .map(|commit| commit.id());
+
+    let discarded = match old_commit_oid {
+        Ok(old_commit_oid) => {
+            if old_commit_oid == new_oid || repo.graph_descendant_of(new_oid, old_commit_oid)? {
+                debug!("Forced push of {} is a fast-forward", dst);
+                return Ok(());
+            }
+            format!(
+                "{} commit(s)",
+                repo.graph_ahead_behind(old_commit_oid, new_oid)?.0
+            )
+        }
+        Err(_) => "commits that are not present locally".to_owned(),
+    };
+
+    confirm_discard(&format!("Forced push of {}", dst), &discarded, config)
+}
+
+/// Makes sure that deleting `dst` from the remote index doesn't discard commits no other ref of
+/// `idx` leads to, unless the user confirms it or `nip.allowForce` is set.
+fn check_deletion(
+    dst: &str,
+    idx: &NIPIndex,
+    repo: &Repository,
+    config: &NipConfig,
+) -> Result<(), Error> {
+    let old_hash = match idx.refs.get(dst) {
+        Some(old_hash) => old_hash,
+        None => return Ok(()),
+    };
+
+    let discarded = match repo
+        .find_object(Oid::from_str(old_hash)?, None)
+        .and_then(|obj| obj.peel(ObjectType::Commit))
+    {
+        Ok(old_commit) => {
+            let mut revwalk = repo.revwalk()?;
+            revwalk.push(old_commit.id())?;
+            for (name, git_hash) in &idx.refs {
+                if name == dst {
+                    continue;
+                }
+                let other_commit = Oid::from_str(git_hash)
+                    .and_then(|oid| repo.find_object(oid, None))
+                    .and_then(|obj| obj.peel(ObjectType::Commit));
+                if let Ok(other_commit) = other_commit {
+                    revwalk.hide(other_commit.id())?;
+                }
+            }
+
+            let count = revwalk.count();
+            if count == 0 {
+                debug!("Everything {} leads to stays reachable on the remote", dst);
+                return Ok(());
+            }
+            format!("{} commit(s)", count)
+        }
+        Err(_) => "commits that are not present locally".to_owned(),
+    };
+
+    confirm_discard(&format!("Deletion of {}", dst), &discarded, config)
+}
+
+/// Lets `operation` go ahead if `nip.allowForce` is set or the user confirms discarding
+/// `discarded` on a terminal.
+fn confirm_discard(operation: &str, discarded: &str, config: &NipConfig) -> Result<(), Error> {
+    if config.allow_force {
+        debug!("nip.allowForce set, {} discards {}", operation, discarded);
+        return Ok(());
+    }
+
+    warn!(
+        "{} would discard {} recorded in the remote index",
+        operation, discarded
+    );
+
+    match confirm_on_tty("Discard them?") {
+        Ok(true) => Ok(()),
+        Ok(false) => bail!("{} cancelled", operation),
+        Err(e) => {
+            debug!("Could not ask for confirmation: {}", e);
+            bail!(
+                "{} would discard {}, set nip.allowForce=true to allow it",
+                operation,
+                discarded
+            );
+        }
+    }
+}
+
+/// Estimates the upload size of pushing `src` and asks for confirmation if it exceeds the
+/// configured large push threshold.
+fn check_push_size(
+    src: &str,
+    dst: &str,
+    idx: &NIPIndex,
+    repo: &Repository,
+    config: &NipConfig,
+) -> Result<(), Error> {
+    if config.force_large {
+        debug!("nip.forceLarge set, skipping the size check");
+        return Ok(());
+    }
+
+    // A zero threshold disables the check
+    let threshold = config.large_push_threshold;
+    if threshold == 0 {
+        return Ok(());
+    }
+
+    let size = match estimate_push_size(src, idx, repo) {
+        Ok(size) => size,
+        Err(e) => {
+            warn!("Could not estimate the size of pushing {}: {}", src, e);
+            return Ok(());
+        }
+    };
+    debug!("Estimated upload size for {}: {} bytes", dst, size);
+
+    if size <= threshold {
+        return Ok(());
+    }
+
+    warn!(
+        "Pushing {} will upload about {} to IPFS",
+        dst,
+        format_size(size)
+    );
+
+    match confirm_on_tty(&format!("Proceed with pushing {}?", dst)) {
+        Ok(true) => Ok(()),
+        Ok(false) => bail!("Push of {} cancelled", dst),
+        Err(e) => {
+            debug!("Could not ask for confirmation: {}", e);
+            bail!(
+                "Push of {} exceeds nip.largePushThreshold ({}), run with `git -c nip.forceLarge=true push` to proceed",
+                dst,
+                format_size(size)
+            );
+        }
+    }
+}
+
+/// Makes sure that pushing `src` from a shallow clone won't need history the clone doesn't have.
+/// That's the case when `idx` already has every shallow boundary commit `src` leads to, as
+/// uploading stops at objects the remote has.
+///
+/// libgit2 doesn't know about the parents a shallow clone is missing, so the history is walked
+/// by hand with the boundary commits as leaves.
+fn check_shallow_push(src: &str, idx: &NIPIndex, repo: &Repository) -> Result<(), Error> {
+    let shallow = match fs::read_to_string(session::common_dir(repo).join("shallow")) {
+        Ok(shallow) => shallow,
+        Err(_) => return Ok(()),
+    };
+    let unshallow_hint = "run `git fetch --unshallow` and push again";
+
+    let src_oid = match repo
+        .revparse_single(src)
+        .and_then(|obj| obj.peel(ObjectType::Commit))
+    {
+        Ok(commit) => commit.id(),
+        Err(_) => return Ok(()),
+    };
+
+    let mut boundaries = HashSet::new();
+    for line in shallow.lines().filter(|line| !line.trim().is_empty()) {
+        boundaries.insert(Oid::from_str(line.trim())?);
+    }
+
+    let mut missing = Vec::new();
+    let mut seen = HashSet::new();
+    let mut pending = vec![src_oid];
+    while let Some(oid) = pending.pop() {
+        if !seen.insert(oid) || idx.objects.contains_key(&oid.to_string()) {
+            continue;
+        }
+        if boundaries.contains(&oid) {
+            missing.push(oid.to_string());
+            continue;
+        }
+
+        let commit = repo.find_commit(oid).map_err(|e| {
+            format_err!(
+                "{} leads to commit {} this shallow clone can't read ({}), {}",
+                src,
+                oid,
+                e,
+                unshallow_hint
+            )
+        })?;
+        pending.extend(commit.parent_ids());
+    }
+
+    if !missing.is_empty() {
+        bail!(
+            "{} reaches past the history of this shallow clone at {} commit(s) the remote doesn't have either (e.g. {}), {}",
+            src,
+            missing.len(),
+            missing[0],
+            unshallow_hint
+        );
+    }
+
+    debug!("All shallow boundaries behind {} are on the remote already", src);
+    Ok(())
+}
+
+/// Sums up the sizes of all objects reachable from `src` that `idx` doesn't contain yet.
+fn estimate_push_size(src: &str, idx: &NIPIndex, repo: &Repository) -> Result<u64, Error> {
+    let odb = repo.odb()?;
+    let mut seen = HashSet::new();
+    let mut total = 0;
+
+    // Count in annotated tags on the way to the tagged object
+    let mut obj = repo.revparse_single(src)?;
+    while obj.kind() == Some(ObjectType::Tag) {
+        if !idx.objects.contains_key(&obj.id().to_string()) && seen.insert(obj.id()) {
+            total += odb.read_header(obj.id())?.0 as u64;
+        }
+        let target = obj
+            .as_tag()
+            .ok_or_else(|| format_err!("{} is not a tag", obj.id()))?
+            .target()?;
+        obj = target;
+    }
+
+    if obj.kind() != Some(ObjectType::Commit) {
+        if !idx.objects.contains_key(&obj.id().to_string()) && seen.insert(obj.id()) {
+            total += odb.read_header(obj.id())?.0 as u64;
+        }
+        return Ok(total);
+    }
+
+    let mut revwalk = repo.revwalk()?;
+    revwalk.push(obj.id())?;
+
+    // Don't walk history the remote is known to have
+    for git_hash in idx.refs.values() {
+        if let Ok(oid) = Oid::from_str(git_hash) {
+            if repo.find_commit(oid).is_ok() {
+                revwalk.hide(oid)?;
+            }
+        }
+    }
+
+    for commit_oid in revwalk {
+        let commit_oid = commit_oid?;
+        if idx.objects.contains_key(&commit_oid.to_string()) || !seen.insert(commit_oid) {
+            continue;
+        }
+        total += odb.read_header(commit_oid)?.0 as u64;
+
+        let tree = repo.find_commit(commit_oid)?.tree()?;
+        if idx.objects.contains_key(&tree.id().to_string()) || !seen.insert(tree.id()) {
+            continue;
+        }
+        total += odb.read_header(tree.id())?.0 as u64;
+
+        tree.walk(TreeWalkMode::PreOrder, |_, entry| {
+            let id = entry.id();
+
+            // Submodule tips don't live in this repo
+            if entry.kind() == Some(ObjectType::Commit)
+                || idx.objects.contains_key(&id.to_string())
+                || !seen.insert(id)
+            {
+                return TreeWalkResult::Skip;
+            }
+
+            total += odb.read_header(id).map(|(size, _)| size as u64).unwrap_or(0);
+            TreeWalkResult::Ok
+        })?;
+    }
+
+    Ok(total)
+}
+
+/// Asks the user a yes/no `question` on the controlling terminal. The helper's stdio belongs to
+/// git, so this fails if there's no terminal to ask or `GIT_TERMINAL_PROMPT=0` forbids asking.
+fn confirm_on_tty(question: &str) -> Result<bool, Error> {
+    if env::var("GIT_TERMINAL_PROMPT").ok().as_deref() == Some("0") {
+        bail!("terminal prompts are disabled by GIT_TERMINAL_PROMPT=0");
+    }
+
+    let mut tty = OpenOptions::new().read(true).write(true).open("/dev/tty")?;
+    write!(tty, "{} [y/N] ", question)?;
+    tty.flush()?;
+
+    let mut answer = String::new();
+    BufReader::new(tty).read_line(&mut answer)?;
+
+    Ok(answer.trim().eq_ignore_ascii_case("y"))
+}
+
@@ -0,0 +1,220 @@
+//! A cache of IPNS name resolutions. IPNS lookups routinely take several seconds, and a single
+//! nip invocation may need the same name more than once; repos additionally keep the cache on
+//! disk so that consecutive invocations benefit too.
+use failure::Error;
+use git2::Repository;
+use ipfs_api::IpfsClient;
+use tokio::{prelude::FutureExt, runtime::current_thread};
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::PathBuf,
+    time::Duration,
+};
+
+use crate::{
+    session::{self, unix_time},
+    trace, NipConfig,
+};
+
+/// IPNS resolutions younger than this many seconds are reused by default
+pub static DEFAULT_IPNS_CACHE_TTL: u64 = 60;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct CachedResolution {
+    /// The `/ipfs/` path the name resolved to
+    path: String,
+    /// Seconds since the Unix epoch
+    resolved_at: u64,
+}
+
+/// IPNS name -> `/ipfs/` path mappings and when they were looked up
+#[derive(Debug)]
+pub struct IpnsCache {
+    /// Where the cache is persisted; `None` keeps it in memory only
+    file_path: Option<PathBuf>,
+    /// Maximum age of a reusable resolution in seconds; 0 disables the cache
+    ttl: u64,
+    /// How long to wait for IPFS to resolve a name
+    timeout: Duration,
+    entries: BTreeMap<String, CachedResolution>,
+}
+
+impl IpnsCache {
+    /// A cache that lives as long as the process does
+    pub fn in_memory(config: &NipConfig) -> Self {
+        Self {
+            file_path: None,
+            ttl: config.ipns_cache_ttl,
+            timeout: Duration::from_secs(config.timeout),
+            entries: BTreeMap::new(),
+        }
+    }
+
+    /// Opens the on-disk cache of `repo`. A cache that can't be read is started over.
+    pub fn load(repo: &Repository, config: &NipConfig) -> Self {
+        let file_path = session::common_dir(repo).join("nip").join("ipns-cache.json");
+
+        let entries = match fs::read_to_string(&file_path) {
+            Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|e| {
+                warn!("Discarding unreadable IPNS cache {}: {}", file_path.display(), e);
+                BTreeMap::new()
+            }),
+            Err(_) => BTreeMap::new(),
+        };
+
+        Self {
+            file_path: Some(file_path),
+            entries,
+            ..Self::in_memory(config)
+        }
+    }
+
+    /// Returns the `/ipfs/` path IPNS `name` points at, consulting IPFS only if there's no fresh
+    /// cached resolution.
+    pub fn resolve(&mut self, name: &str, ipfs: &mut IpfsClient) -> Result<String, Error> {
+        if let Some(path) = self.get(name) {
+            debug!("Using cached resolution {} -> {}", name, path);
+            return Ok(path);
+        }
+
+        let resolved = trace::ipfs(
+            &format!("name/resolve {}", name),
+            current_thread::block_on_all(
+                ipfs.name_resolve(Some(name), true, false).timeout(self.timeout),
+            ),
+        )
+        .map_err(|e| {
+            if e.is_elapsed() {
+                format_err!("Resolving {} timed out after {:?}", name, self.timeout)
+            } else {
+                format_err!("Could not resolve {}: {}", name, e)
+            }
+        })?;
+        debug!("Resolved {} -> {}", name, resolved.path);
+
+        self.insert(name, &resolved.path);
+
+        Ok(resolved.path)
+    }
+
+    /// Returns the cached resolution of `name` if it's still fresh
+    pub fn get(&self, name: &str) -> Option<String> {
+        if self.ttl == 0 {
+            return None;
+        }
+
+        self.entries
+            .get(name)
+            .filter(|entry| unix_time().saturating_sub(entry.resolved_at) < self.ttl)
+            .map(|entry| entry.path.clone())
+    }
+
+    /// Records that `name` points at `path`, e.g. right after publishing it
+    pub fn insert(&mut self, name: &str, path: &str) {
+        if self.ttl == 0 {
+            return;
+        }
+
+        self.entries.insert(
+            name.to_owned(),
+            CachedResolution {
+                path: path.to_owned(),
+                resolved_at: unix_time(),
+            },
+        );
+        self.save();
+    }
+
+    /// Forgets `name`, e.g. after it was republished without us learning the new value
+    pub fn invalidate(&mut self, name: &str) {
+        if self.entries.remove(name).is_some() {
+            self.save();
+        }
+    }
+
+    /// Persists the fresh entries. Failing to do so only costs performance, so it's not an error.
+    fn save(&mut self) {
+        let file_path = match self.file_path {
+            Some(ref file_path) => file_path.clone(),
+            None => return,
+        };
+
+        // Drop what has gone stale along the way
+        let (ttl, now_secs) = (self.ttl, unix_time());
+        self.entries
+            .retain(|_, entry| now_secs.saturating_sub(entry.resolved_at) < ttl);
+
+        let res = serde_json::to_string(&self.entries)
+            .map_err(Error::from)
+            .and_then(|contents| session::write_atomically(&file_path, &contents));
+        if let Err(e) = res {
+            warn!("Could not save IPNS cache {}: {}", file_path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{env, process};
+
+    fn cache_with_ttl(ttl: u64) -> IpnsCache {
+        IpnsCache::in_memory(&NipConfig {
+            ipns_cache_ttl: ttl,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn fresh_entries_are_reused() {
+        let mut cache = cache_with_ttl(60);
+        cache.insert("QmName", "/ipfs/QmPath");
+
+        assert_eq!(cache.get("QmName"), Some("/ipfs/QmPath".to_owned()));
+        assert_eq!(cache.get("QmOtherName"), None);
+    }
+
+    #[test]
+    fn stale_entries_expire() {
+        let mut cache = cache_with_ttl(60);
+        cache.insert("QmName", "/ipfs/QmPath");
+        cache.entries.get_mut("QmName").unwrap().resolved_at = unix_time() - 60;
+
+        assert_eq!(cache.get("QmName"), None);
+    }
+
+    #[test]
+    fn zero_ttl_disables_the_cache() {
+        let mut cache = cache_with_ttl(0);
+        cache.insert("QmName", "/ipfs/QmPath");
+
+        assert!(cache.entries.is_empty());
+        assert_eq!(cache.get("QmName"), None);
+    }
+
+    #[test]
+    fn invalidated_entries_are_forgotten() {
+        let mut cache = cache_with_ttl(60);
+        cache.insert("QmName", "/ipfs/QmPath");
+        cache.invalidate("QmName");
+
+        assert_eq!(cache.get("QmName"), None);
+    }
+
+    #[test]
+    fn entries_survive_a_reload() {
+        let repo_path = env::temp_dir().join(format!("nip-ipns-cache-{}", process::id()));
+        let _ = fs::remove_dir_all(&repo_path);
+        let repo = Repository::init(&repo_path).unwrap();
+        let config = NipConfig::default();
+
+        IpnsCache::load(&repo, &config).insert("QmName", "/ipfs/QmPath");
+
+        let reloaded = IpnsCache::load(&repo, &config);
+        fs::remove_dir_all(&repo_path).unwrap();
+        assert_eq!(reloaded.get("QmName"), Some("/ipfs/QmPath".to_owned()));
+    }
+}
@@ -0,0 +1,24 @@
+//! Functionality shared by `git-remote-nip` and `nipctl`
+#[macro_use]
+extern crate failure;
+#[macro_use]
+extern crate log;
+#[macro_use]
+extern crate serde_derive;
+
+pub mod config;
+pub mod fetch;
+pub mod ipns_cache;
+pub mod logging;
+pub mod mfs;
+pub mod nip_ffi;
+pub mod session;
+pub mod state;
+pub mod trace;
+
+pub use crate::{
+    config::{NipConfig, PartialNipConfig, SignaturePolicy, UrlUpdatePolicy},
+    ipns_cache::IpnsCache,
+    session::SessionLock,
+    state::{IndexObservation, NipState, RemoteState},
+};
@@ -0,0 +1,103 @@
+//! Session logs for the remote helper. git owns the helper's stdout and shows stderr to the user,
+//! so a full trace of what happened during a session is instead written to a file under
+//! `.git/nip/logs/`, next to the usual stderr output.
+use env_logger::Logger;
+use failure::Error;
+use git2::Repository;
+use log::{LevelFilter, Log, Metadata, Record};
+
+use std::{
+    env,
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+    process,
+    sync::Mutex,
+};
+
+use crate::session::{self, unix_time};
+
+/// How many session logs are kept around; older ones are removed when a new session starts
+pub static SESSION_LOGS_KEPT: usize = 20;
+
+/// Logs to stderr with the usual filtering and everything down to `trace` to a file
+struct SessionLogger {
+    stderr: Logger,
+    file: Mutex<File>,
+}
+
+impl Log for SessionLogger {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        if self.stderr.matches(record) {
+            self.stderr.log(record);
+        }
+
+        // A broken log file must not break the session it's describing
+        if let Ok(mut file) = self.file.lock() {
+            let _ = writeln!(
+                file,
+                "{} {:<5} {}: {}",
+                unix_time(),
+                record.level(),
+                record.target(),
+                record.args()
+            );
+        }
+    }
+
+    fn flush(&self) {
+        self.stderr.flush();
+        if let Ok(mut file) = self.file.lock() {
+            let _ = file.flush();
+        }
+    }
+}
+
+/// Installs a logger that writes to stderr at `level` (`RUST_LOG` still takes precedence) and
+/// traces everything to a new session log of `repo`. Returns the log's path.
+pub fn init_session_logging(repo: &Repository, level: LevelFilter) -> Result<PathBuf, Error> {
+    let logs_dir = session::nip_dir(repo)?.join("logs");
+    fs::create_dir_all(&logs_dir)
+        .map_err(|e| format_err!("Could not create {}: {}", logs_dir.display(), e))?;
+    prune_session_logs(&logs_dir);
+
+    let path = logs_dir.join(format!("{}-{}.log", unix_time(), process::id()));
+    let file = File::create(&path)
+        .map_err(|e| format_err!("Could not create {}: {}", path.display(), e))?;
+
+    let mut builder = env_logger::Builder::new();
+    builder.filter(None, level);
+    if let Ok(filters) = env::var("RUST_LOG") {
+        builder.parse(&filters);
+    }
+
+    log::set_boxed_logger(Box::new(SessionLogger {
+        stderr: builder.build(),
+        file: Mutex::new(file),
+    }))?;
+    log::set_max_level(LevelFilter::Trace);
+
+    Ok(path)
+}
+
+/// Removes all but the newest `SESSION_LOGS_KEPT - 1` logs in `logs_dir`, making room for one
+/// more. Log names start with a timestamp, so sorting them by name sorts them by age.
+fn prune_session_logs(logs_dir: &Path) {
+    let mut logs: Vec<PathBuf> = match fs::read_dir(logs_dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().map_or(false, |ext| ext == "log"))
+            .collect(),
+        Err(_) => return,
+    };
+    logs.sort();
+
+    let excess = (logs.len() + 1).saturating_sub(SESSION_LOGS_KEPT);
+    for old_log in logs.iter().take(excess) {
+        let _ = fs::remove_file(old_log);
+    }
+}
@@ -0,0 +1,110 @@
+//! Browsable copies of git trees as UnixFS directories, put together in the daemon's Mutable File
+//! System. The raw data nip uploads for a blob is already a UnixFS file holding the blob's
+//! contents, so only the directories are new; no file data is uploaded again.
+use failure::Error;
+use git2::{ObjectType, Oid, Repository, Tree};
+use ipfs_api::IpfsClient;
+use nip_core::NIPIndex;
+use tokio::runtime::current_thread;
+
+use crate::{
+    fetch::{fetch_object, NIP_SUBMODULE_TIP},
+    trace,
+};
+
+/// The git file mode of symbolic links
+static SYMLINK_MODE: i32 = 0o120_000;
+
+/// Replaces the MFS directory `dir` with the contents of `tree`, whose blobs must all be objects
+/// of `idx`, and returns the directory's IPFS hash. The directory is assembled next to `dir` and
+/// moved into place at the end, so `dir` never shows a half-written tree.
+///
+/// UnixFS has no notion of file modes: the executable bit is lost, and symlinks and submodules
+/// are left out.
+pub fn write_tree(
+    repo: &Repository,
+    tree: &Tree,
+    idx: &NIPIndex,
+    dir: &str,
+    ipfs: &mut IpfsClient,
+) -> Result<String, Error> {
+    let staging_dir = format!("{}.nip-tmp", dir);
+
+    // Leftovers of an interrupted run; a missing directory is fine
+    let _ = current_thread::block_on_all(ipfs.files_rm(&staging_dir, true));
+    trace::ipfs(
+        &format!("files/mkdir {}", staging_dir),
+        current_thread::block_on_all(ipfs.files_mkdir(&staging_dir, true)),
+    )
+    .map_err(|e| format_err!("Could not create {}: {}", staging_dir, e))?;
+
+    copy_tree_entries(repo, tree, idx, &staging_dir, ipfs)?;
+
+    let _ = current_thread::block_on_all(ipfs.files_rm(dir, true));
+    trace::ipfs(
+        &format!("files/mv {} {}", staging_dir, dir),
+        current_thread::block_on_all(ipfs.files_mv(&staging_dir, dir)),
+    )
+    .map_err(|e| format_err!("Could not move {} to {}: {}", staging_dir, dir, e))?;
+
+    let stat = trace::ipfs(
+        &format!("files/stat {}", dir),
+        current_thread::block_on_all(ipfs.files_stat(dir)),
+    )
+    .map_err(|e| format_err!("Could not stat {}: {}", dir, e))?;
+
+    Ok(stat.hash)
+}
+
+fn copy_tree_entries(
+    repo: &Repository,
+    tree: &Tree,
+    idx: &NIPIndex,
+    dir: &str,
+    ipfs: &mut IpfsClient,
+) -> Result<(), Error> {
+    for entry in tree.iter() {
+        let name = entry
+            .name()
+            .ok_or_else(|| format_err!("{}: {} has a non-UTF-8 name", dir, entry.id()))?;
+        let path = format!("{}/{}", dir, name);
+
+        match entry.kind() {
+            Some(ObjectType::Tree) => {
+                trace::ipfs(
+                    &format!("files/mkdir {}", path),
+                    current_thread::block_on_all(ipfs.files_mkdir(&path, false)),
+                )
+                .map_err(|e| format_err!("Could not create {}: {}", path, e))?;
+
+                copy_tree_entries(repo, &repo.find_tree(entry.id())?, idx, &path, ipfs)?;
+            }
+            Some(ObjectType::Blob) if entry.filemode() == SYMLINK_MODE => {
+                warn!("Leaving out symlink {}", path);
+            }
+            Some(ObjectType::Blob) => {
+                let raw_data_path = format!("/ipfs/{}", raw_data_ipfs_hash(idx, entry.id(), ipfs)?);
+                trace::ipfs(
+                    &format!("files/cp {} {}", raw_data_path, path),
+                    current_thread::block_on_all(ipfs.files_cp(&raw_data_path, &path)),
+                )
+                .map_err(|e| format_err!("Could not copy {} to {}: {}", raw_data_path, path, e))?;
+                debug!("{} -> {}", path, raw_data_path);
+            }
+            _ => debug!("Leaving out submodule {}", path),
+        }
+    }
+
+    Ok(())
+}
+
+/// Looks up the IPFS hash of the raw data of blob `oid` in `idx`
+fn raw_data_ipfs_hash(idx: &NIPIndex, oid: Oid, ipfs: &mut IpfsClient) -> Result<String, Error> {
+    let git_hash = oid.to_string();
+    let obj_ipfs_hash = match idx.objects.get(&git_hash) {
+        Some(obj_ipfs_hash) if obj_ipfs_hash != NIP_SUBMODULE_TIP => obj_ipfs_hash,
+        _ => bail!("{} is not an object of the index", git_hash),
+    };
+
+    Ok(fetch_object(obj_ipfs_hash, &git_hash, ipfs)?.raw_data_ipfs_hash)
+}
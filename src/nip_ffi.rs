@@ -0,0 +1,296 @@
+//! A C ABI for working with nip repos from non-Rust code; `include/nip.h` declares it.
+//!
+//! All functions taking pointers expect them to be either null or valid for the access implied
+//! by the declaration. Strings returned by nip are owned by the caller and must be released with
+//! `nip_string_free`. Functions returning `int` return 0 on success and -1 on failure; details of
+//! the most recent failure on the calling thread are available from `nip_last_error`. Panics
+//! never cross into C, they're reported like any other failure.
+#![allow(clippy::missing_safety_doc)]
+
+use failure::Error;
+use git2::Repository;
+use ipfs_api::IpfsClient;
+use nip_core::{NIPIndex, NIPRemote};
+
+use std::{
+    cell::RefCell,
+    ffi::{CStr, CString},
+    os::raw::{c_char, c_int},
+    panic::{self, AssertUnwindSafe},
+    ptr,
+};
+
+use crate::fetch::fetch_index;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = RefCell::new(None);
+}
+
+/// An index together with the remote it came from and the IPFS connection to use for it
+pub struct NipIndexHandle {
+    idx: NIPIndex,
+    nip_remote: NIPRemote,
+    ipfs: IpfsClient,
+}
+
+impl NipIndexHandle {
+    fn load(api_host: &str, api_port: u16, url: &str) -> Result<Self, Error> {
+        let nip_remote: NIPRemote = url.trim_start_matches("nip::").parse()?;
+        let ipfs_hash = nip_remote
+            .get_hash()
+            .ok_or_else(|| format_err!("{} is not an existing nip repo", url))?;
+
+        let mut ipfs = IpfsClient::new(api_host, api_port)
+            .map_err(|e| format_err!("Invalid IPFS API endpoint: {}", e))?;
+
+        let idx = fetch_index(&ipfs_hash, &mut ipfs)?;
+
+        Ok(Self {
+            idx,
+            nip_remote,
+            ipfs,
+        })
+    }
+
+    unsafe fn fetch(
+        &mut self,
+        repo_path: *const c_char,
+        git_hash: *const c_char,
+        ref_name: *const c_char,
+    ) -> Result<(), Error> {
+        let mut repo = Repository::open(to_str(repo_path, "repo_path")?)?;
+
+        self.idx.fetch_to_ref_from_str(
+            to_str(git_hash, "git_hash")?,
+            to_str(ref_name, "ref_name")?,
+            &mut repo,
+            &mut self.ipfs,
+        )?;
+        Ok(())
+    }
+
+    unsafe fn push(
+        &mut self,
+        repo_path: *const c_char,
+        src: *const c_char,
+        dst: *const c_char,
+        force: bool,
+    ) -> Result<(), Error> {
+        let mut repo = Repository::open(to_str(repo_path, "repo_path")?)?;
+
+        self.idx.push_ref_from_str(
+            to_str(src, "src")?,
+            to_str(dst, "dst")?,
+            force,
+            &mut repo,
+            &mut self.ipfs,
+        )?;
+        Ok(())
+    }
+
+    fn publish(&mut self) -> Result<String, Error> {
+        let new_remote = self.idx.ipfs_add(&mut self.ipfs, Some(&self.nip_remote))?;
+        let new_hash = new_remote
+            .get_hash()
+            .ok_or_else(|| format_err!("The uploaded index has no hash"))?;
+        self.nip_remote = new_remote;
+
+        Ok(new_hash)
+    }
+}
+
+/// Downloads the index the nip URL `url` (e.g. `nip::/ipfs/Qm...`) points at through the IPFS
+/// API at `api_host`:`api_port`. Returns null on failure.
+#[no_mangle]
+pub unsafe extern "C" fn nip_index_load(
+    api_host: *const c_char,
+    api_port: u16,
+    url: *const c_char,
+) -> *mut NipIndexHandle {
+    guard(ptr::null_mut(), || {
+        let res = to_str(api_host, "api_host")
+            .and_then(|api_host| NipIndexHandle::load(api_host, api_port, to_str(url, "url")?));
+
+        match res {
+            Ok(handle) => Box::into_raw(Box::new(handle)),
+            Err(e) => {
+                set_last_error(&e);
+                ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// Releases an index obtained from `nip_index_load`
+#[no_mangle]
+pub unsafe extern "C" fn nip_index_free(handle: *mut NipIndexHandle) {
+    guard((), || {
+        if !handle.is_null() {
+            drop(Box::from_raw(handle));
+        }
+    })
+}
+
+/// Returns how many refs the index has
+#[no_mangle]
+pub unsafe extern "C" fn nip_index_ref_count(handle: *const NipIndexHandle) -> usize {
+    guard(0, || {
+        handle.as_ref().map_or(0, |handle| handle.idx.refs.len())
+    })
+}
+
+/// Returns the name of the `n`th ref in lexicographic order, or null if there's no such ref
+#[no_mangle]
+pub unsafe extern "C" fn nip_index_ref_name(
+    handle: *const NipIndexHandle,
+    n: usize,
+) -> *mut c_char {
+    guard(ptr::null_mut(), || {
+        match handle
+            .as_ref()
+            .and_then(|handle| handle.idx.refs.keys().nth(n))
+        {
+            Some(name) => to_c_string(name),
+            None => ptr::null_mut(),
+        }
+    })
+}
+
+/// Returns the git hash the `n`th ref points at, or null if there's no such ref
+#[no_mangle]
+pub unsafe extern "C" fn nip_index_ref_target(
+    handle: *const NipIndexHandle,
+    n: usize,
+) -> *mut c_char {
+    guard(ptr::null_mut(), || {
+        match handle
+            .as_ref()
+            .and_then(|handle| handle.idx.refs.values().nth(n))
+        {
+            Some(git_hash) => to_c_string(git_hash),
+            None => ptr::null_mut(),
+        }
+    })
+}
+
+/// Downloads `git_hash` and everything it references into the repo at `repo_path`. If `git_hash`
+/// is a commit, `ref_name` is pointed at it; refs for other objects, e.g. annotated tags, are up
+/// to the caller.
+#[no_mangle]
+pub unsafe extern "C" fn nip_fetch(
+    handle: *mut NipIndexHandle,
+    repo_path: *const c_char,
+    git_hash: *const c_char,
+    ref_name: *const c_char,
+) -> c_int {
+    guard(-1, || {
+        to_status(handle_mut(handle).and_then(|handle| handle.fetch(repo_path, git_hash, ref_name)))
+    })
+}
+
+/// Uploads `src` from the repo at `repo_path` and points `dst` at it in the index. The change
+/// only becomes visible to others after `nip_index_publish`.
+#[no_mangle]
+pub unsafe extern "C" fn nip_push(
+    handle: *mut NipIndexHandle,
+    repo_path: *const c_char,
+    src: *const c_char,
+    dst: *const c_char,
+    force: c_int,
+) -> c_int {
+    guard(-1, || {
+        to_status(
+            handle_mut(handle).and_then(|handle| handle.push(repo_path, src, dst, force != 0)),
+        )
+    })
+}
+
+/// Uploads the index as the next generation of its remote, republishing IPNS remotes. Returns
+/// the new IPFS/IPNS path of the remote, or null on failure.
+#[no_mangle]
+pub unsafe extern "C" fn nip_index_publish(handle: *mut NipIndexHandle) -> *mut c_char {
+    guard(ptr::null_mut(), || {
+        match handle_mut(handle).and_then(NipIndexHandle::publish) {
+            Ok(new_hash) => to_c_string(&new_hash),
+            Err(e) => {
+                set_last_error(&e);
+                ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// Describes the most recent failure on the calling thread, or returns null if there was none.
+/// The string belongs to nip and stays valid until the next failing call on the same thread.
+#[no_mangle]
+pub extern "C" fn nip_last_error() -> *const c_char {
+    guard(ptr::null(), || {
+        LAST_ERROR.with(|last_error| {
+            last_error
+                .borrow()
+                .as_ref()
+                .map_or(ptr::null(), |msg| msg.as_ptr())
+        })
+    })
+}
+
+/// Releases a string returned by nip
+#[no_mangle]
+pub unsafe extern "C" fn nip_string_free(s: *mut c_char) {
+    guard((), || {
+        if !s.is_null() {
+            drop(CString::from_raw(s));
+        }
+    })
+}
+
+/// Runs `f`, turning a panic into the `failed` return value and a last error, since unwinding
+/// into C is undefined behaviour
+fn guard<T, F: FnOnce() -> T>(failed: T, f: F) -> T {
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|payload| {
+        let msg = payload
+            .downcast_ref::<&str>()
+            .map(|msg| msg.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown cause".to_owned());
+        set_last_error(&format_err!("nip panicked: {}", msg));
+        failed
+    })
+}
+
+unsafe fn handle_mut<'a>(handle: *mut NipIndexHandle) -> Result<&'a mut NipIndexHandle, Error> {
+    handle
+        .as_mut()
+        .ok_or_else(|| format_err!("handle must not be null"))
+}
+
+unsafe fn to_str<'a>(s: *const c_char, arg_name: &str) -> Result<&'a str, Error> {
+    if s.is_null() {
+        bail!("{} must not be null", arg_name);
+    }
+    CStr::from_ptr(s)
+        .to_str()
+        .map_err(|e| format_err!("{} is not valid UTF-8: {}", arg_name, e))
+}
+
+/// Hands `s` over to C; interior NUL bytes, which git and IPFS names never contain, are dropped.
+fn to_c_string(s: &str) -> *mut c_char {
+    CString::new(s.replace('\0', ""))
+        .map(CString::into_raw)
+        .unwrap_or(ptr::null_mut())
+}
+
+fn to_status(res: Result<(), Error>) -> c_int {
+    match res {
+        Ok(()) => 0,
+        Err(e) => {
+            set_last_error(&e);
+            -1
+        }
+    }
+}
+
+fn set_last_error(e: &Error) {
+    let msg = CString::new(e.to_string().replace('\0', "")).ok();
+    LAST_ERROR.with(|last_error| *last_error.borrow_mut() = msg);
+}
@@ -2,33 +2,98 @@
 extern crate log;
 
 extern crate clap;
+extern crate colored;
+#[macro_use]
 extern crate failure;
 extern crate git2;
+extern crate hyper;
+extern crate hyper_tls;
 extern crate ipfs_api;
 extern crate serde_json;
 extern crate tokio;
 
+extern crate nip;
 extern crate nip_core;
 
 use clap::{App, Arg, ArgMatches, SubCommand};
+use colored::*;
 use failure::Error;
-use ipfs_api::IpfsClient;
+use git2::{BranchType, Config, ObjectType, Oid, Repository, TreeWalkMode, TreeWalkResult};
+use hyper::{client::HttpConnector, Body, Client, Request, Uri};
+use hyper_tls::HttpsConnector;
+use ipfs_api::{
+    response::{DhtType, Error as IpfsError},
+    IpfsClient,
+};
 use log::LevelFilter;
-use tokio::runtime::Runtime;
+use tokio::{
+    prelude::{Future, FutureExt, Stream},
+    runtime::Runtime,
+};
 
-use std::{process, str::FromStr};
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    env, fs,
+    io::{self, BufRead, Write},
+    path::Path,
+    process,
+    str::FromStr,
+    time::Duration,
+};
 
+use nip::{
+    config::format_size,
+    fetch::{fetch_index, fetch_object, resolve_to_ipfs_path, NIP_SUBMODULE_TIP},
+    session, IpnsCache, NipConfig, NipState, PartialNipConfig,
+};
 use nip_core::{
-    init_logging, ipfs_cat, migrate_index, migrate_object, parse_nip_header, NIPIndex, NIPRemote,
-    NIP_HEADER_LEN, NIP_PROTOCOL_VERSION,
+    init_logging, ipfs_cat, migrate_index, migrate_object, parse_nip_header, NIPIndex,
+    NIPObjectMetadata, NIPRemote, NIP_HEADER_LEN, NIP_PROTOCOL_VERSION,
 };
 
-pub fn main() {
-    init_logging(LevelFilter::Info);
+/// The gateway `nipctl health` retrieves blocks through unless told otherwise
+static DEFAULT_HEALTH_GATEWAY: &str = "https://ipfs.io";
 
+pub fn main() {
     let cli_matches = App::new("nipctl")
         .version(env!("CARGO_PKG_VERSION"))
         .about("The repo administration utility for nip.")
+        .arg(
+            Arg::with_name("api")
+            .long("--api")
+            .value_name("MULTIADDR")
+            .help("The IPFS API endpoint as a multiaddr (e.g. /ip4/127.0.0.1/tcp/5001) or HOST:PORT; Overrides nip.api"),
+            )
+        .arg(
+            Arg::with_name("api_host")
+            .long("--api-host")
+            .value_name("HOST")
+            .help("Host of the IPFS API; Overrides nip.apiHost"),
+            )
+        .arg(
+            Arg::with_name("api_port")
+            .long("--api-port")
+            .value_name("PORT")
+            .help("Port of the IPFS API; Overrides nip.apiPort"),
+            )
+        .arg(
+            Arg::with_name("log_level")
+            .long("--log-level")
+            .value_name("LEVEL")
+            .help("Default log level (error, warn, info, debug or trace); Overrides nip.logLevel"),
+            )
+        .arg(
+            Arg::with_name("json")
+            .short("j")
+            .long("--json")
+            .global(true)
+            .help("Print results in JSON instead of human-readable text"),
+            )
+        .arg(
+            Arg::with_name("no_cache")
+            .long("--no-cache")
+            .help("Resolve IPNS names afresh instead of reusing recent resolutions"),
+            )
         .subcommand(
             SubCommand::with_name("list")
             .about("Prints out a nip IPFS/IPNS link of any type human-readably")
@@ -45,16 +110,444 @@ pub fn main() {
                 .value_name("N")
                 .help("When listing an index move at most N steps back using the previous IPFS hash index field; Ignored for objects"),
                 )
+            )
+        .subcommand(
+            SubCommand::with_name("squash")
+            .about("Rewrites an index chain so that only the most recent generations remain")
+            .arg(
+                Arg::with_name("ipfs_hash")
+                .help("The IPFS/IPNS hash of the index chain head")
+                .required(true)
+                .index(1),
+                )
+            .arg(
+                Arg::with_name("keep")
+                .short("k")
+                .long("--keep")
+                .value_name("N")
+                .help("How many previous generations to keep behind the head; Defaults to 0"),
+                )
+            .arg(
+                Arg::with_name("unpin")
+                .short("u")
+                .long("--unpin")
+                .help("Unpin the original generations from the local IPFS node after squashing")
+                )
+            .arg(
+                Arg::with_name("remote")
+                .long("--remote")
+                .value_name("NAME")
+                .help("Apply the remote.<NAME>.nip-* settings of the current repo, e.g. its IPNS key"),
+                )
+            )
+        .subcommand(
+            SubCommand::with_name("refs")
+            .about("Compares the refs of a nip index with the branches of a local repo")
+            .arg(
+                Arg::with_name("ipfs_hash")
+                .help("The IPFS/IPNS hash of the index to compare")
+                .required(true)
+                .index(1),
+                )
+            .arg(
+                Arg::with_name("repo")
+                .long("--repo")
+                .value_name("PATH")
+                .help("The local repo to compare against; Defaults to the current directory"),
+                )
+            )
+        .subcommand(
+            SubCommand::with_name("diff-index")
+            .about("Lists objects reachable from local branches and tags that a nip index is missing, and vice versa")
+            .arg(
+                Arg::with_name("ipfs_hash")
+                .help("The IPFS/IPNS hash of the index to compare")
+                .required(true)
+                .index(1),
+                )
+            .arg(
+                Arg::with_name("repo")
+                .long("--repo")
+                .value_name("PATH")
+                .help("The local repo to compare against; Defaults to the current directory"),
+                )
+            )
+        .subcommand(
+            SubCommand::with_name("reprovide")
+            .about("Announces the index, objects and raw data of a nip repo to the DHT")
+            .arg(
+                Arg::with_name("ipfs_hash")
+                .help("The IPFS/IPNS hash of the index to announce")
+                .required(true)
+                .index(1),
+                )
+            )
+        .subcommand(
+            SubCommand::with_name("health")
+            .about("Checks whether the blocks of a nip repo can be retrieved by other peers")
+            .arg(
+                Arg::with_name("ipfs_hash")
+                .help("The IPFS/IPNS hash of the index to check")
+                .required(true)
+                .index(1),
+                )
+            .arg(
+                Arg::with_name("via_gateway")
+                .short("g")
+                .long("--via-gateway")
+                .value_name("URL")
+                .help("Retrieve the blocks through the HTTP gateway at URL; Defaults to https://ipfs.io"),
+                )
+            .arg(
+                Arg::with_name("providers_only")
+                .short("p")
+                .long("--providers-only")
+                .conflicts_with("via_gateway")
+                .help("Only check that another peer announces each block in the DHT, without retrieving anything"),
+                )
+            .arg(
+                Arg::with_name("sample")
+                .short("s")
+                .long("--sample")
+                .value_name("N")
+                .help("Only check N evenly spread blocks instead of all of them"),
+                )
+            .arg(
+                Arg::with_name("timeout")
+                .short("t")
+                .long("--timeout")
+                .value_name("SECS")
+                .help("How long to wait for each block before considering it unavailable; Defaults to nip.timeout"),
+                )
+            )
+        .subcommand(
+            SubCommand::with_name("restore")
+            .about("Recreates a nip repo in a new local repository without going through git-remote-nip")
+            .arg(
+                Arg::with_name("ipfs_hash")
+                .help("The IPFS/IPNS hash of the index to restore")
+                .required(true)
+                .index(1),
+                )
+            .arg(
+                Arg::with_name("dir")
+                .help("Where to create the repository; Must not exist or be empty")
+                .required(true)
+                .index(2),
+                )
+            )
+        .subcommand(
+            SubCommand::with_name("archive")
+            .about("Writes the tree of a commit stored in a nip repo to a tar or zip archive, like git archive")
+            .arg(
+                Arg::with_name("ipfs_hash")
+                .help("The IPFS/IPNS hash of the index to take the commit from")
+                .required(true)
+                .index(1),
+                )
+            .arg(
+                Arg::with_name("ref")
+                .help("The ref (e.g. master or refs/tags/v1.0) or commit hash to archive")
+                .required(true)
+                .index(2),
+                )
+            .arg(
+                Arg::with_name("output")
+                .short("o")
+                .long("--output")
+                .value_name("FILE")
+                .required(true)
+                .help("Where to write the archive; The format (.tar, .tar.gz, .tgz or .zip) is picked by extension"),
+                )
+            )
+        .subcommand(
+            SubCommand::with_name("snapshot")
+            .about("Publishes the tree of a commit stored in a nip repo as a plain UnixFS directory, browsable through any gateway")
+            .arg(
+                Arg::with_name("ipfs_hash")
+                .help("The IPFS/IPNS hash of the index to take the commit from")
+                .required(true)
+                .index(1),
+                )
+            .arg(
+                Arg::with_name("ref")
+                .help("The ref (e.g. master or refs/tags/v1.0) or commit hash to snapshot")
+                .required(true)
+                .index(2),
+                )
+            )
+        .subcommand(
+            SubCommand::with_name("bundle")
+            .about("Writes refs of a nip repo to a git bundle that can be cloned without nip or IPFS")
+            .arg(
+                Arg::with_name("ipfs_hash")
+                .help("The IPFS/IPNS hash of the index to take the refs from")
+                .required(true)
+                .index(1),
+                )
+            .arg(
+                Arg::with_name("ref")
+                .help("The refs (e.g. master or refs/tags/v1.0) to include")
+                .required(true)
+                .multiple(true)
+                .index(2),
+                )
+            .arg(
+                Arg::with_name("output")
+                .short("o")
+                .long("--output")
+                .value_name("FILE")
+                .required(true)
+                .help("Where to write the bundle"),
+                )
+            )
+        .subcommand(
+            SubCommand::with_name("export-index")
+            .about("Writes a nip index out as JSON")
+            .arg(
+                Arg::with_name("ipfs_hash")
+                .help("The IPFS/IPNS hash of the index to export")
+                .required(true)
+                .index(1),
+                )
+            .arg(
+                Arg::with_name("output")
+                .short("o")
+                .long("--output")
+                .value_name("FILE")
+                .help("Where to write the JSON; Defaults to stdout"),
+                )
+            )
+        .subcommand(
+            SubCommand::with_name("import-index")
+            .about("Reads a nip index from JSON, e.g. one written by export-index")
+            .arg(
+                Arg::with_name("file")
+                .help("The JSON file to read")
+                .required(true)
+                .index(1),
+                )
+            .arg(
+                Arg::with_name("publish")
+                .short("p")
+                .long("--publish")
+                .help("Upload the index to IPFS and print its hash; Without it the file is only checked"),
+                )
+            )
+        .subcommand(
+            SubCommand::with_name("ref")
+            .about("Edits the refs of a nip index and uploads the result")
+            .subcommand(
+                SubCommand::with_name("set")
+                .about("Points a ref at an object of the index, creating the ref if necessary")
+                .arg(
+                    Arg::with_name("ipfs_hash")
+                    .help("The IPFS/IPNS hash of the index to edit")
+                    .required(true)
+                    .index(1),
+                    )
+                .arg(
+                    Arg::with_name("ref")
+                    .help("The full name of the ref, e.g. refs/heads/master")
+                    .required(true)
+                    .index(2),
+                    )
+                .arg(
+                    Arg::with_name("git_hash")
+                    .help("The git hash to point the ref at")
+                    .required(true)
+                    .index(3),
+                    )
+                )
+            .subcommand(
+                SubCommand::with_name("delete")
+                .about("Removes a ref from the index")
+                .arg(
+                    Arg::with_name("ipfs_hash")
+                    .help("The IPFS/IPNS hash of the index to edit")
+                    .required(true)
+                    .index(1),
+                    )
+                .arg(
+                    Arg::with_name("ref")
+                    .help("The full name of the ref, e.g. refs/heads/master")
+                    .required(true)
+                    .index(2),
+                    )
+                )
+            )
+        .subcommand(
+            SubCommand::with_name("object")
+            .about("Works with individual objects of a nip index")
+            .subcommand(
+                SubCommand::with_name("get")
+                .about("Downloads the raw data of a single object")
+                .arg(
+                    Arg::with_name("ipfs_hash")
+                    .help("The IPFS/IPNS hash of the index containing the object")
+                    .required(true)
+                    .index(1),
+                    )
+                .arg(
+                    Arg::with_name("git_hash")
+                    .help("The git hash of the object to download")
+                    .required(true)
+                    .index(2),
+                    )
+                .arg(
+                    Arg::with_name("output")
+                    .short("o")
+                    .long("--output")
+                    .value_name("FILE")
+                    .required(true)
+                    .help("Where to write the object data"),
+                    )
+                .arg(
+                    Arg::with_name("with_header")
+                    .long("--with-header")
+                    .help("Prepend git's \"<type> <size>\\0\" object header, making the file's SHA-1 equal to the git hash"),
+                    )
+                )
+            .subcommand(
+                SubCommand::with_name("drop")
+                .about("Removes an object from the index and uploads the result")
+                .arg(
+                    Arg::with_name("ipfs_hash")
+                    .help("The IPFS/IPNS hash of the index to edit")
+                    .required(true)
+                    .index(1),
+                    )
+                .arg(
+                    Arg::with_name("git_hash")
+                    .help("The git hash of the object to drop")
+                    .required(true)
+                    .index(2),
+                    )
+                )
+            )
+        .subcommand(
+            SubCommand::with_name("reflog")
+            .about("Shows the values a ref had over the generations of an index chain, newest first")
+            .arg(
+                Arg::with_name("ipfs_hash")
+                .help("The IPFS/IPNS hash of the index chain head")
+                .required(true)
+                .index(1),
+                )
+            .arg(
+                Arg::with_name("ref")
+                .help("The ref, branch or tag name to look up")
+                .required(true)
+                .index(2),
+                )
+            .arg(
+                Arg::with_name("max_generations")
+                .short("n")
+                .long("--max-generations")
+                .value_name("N")
+                .help("Visit at most N generations; Defaults to the whole chain"),
+                )
+            )
+        .subcommand(
+            SubCommand::with_name("du")
+            .about("Shows how much raw data each branch and tag holds on its own and how much they share")
+            .arg(
+                Arg::with_name("ipfs_hash")
+                .help("The IPFS/IPNS hash of the index to inspect")
+                .required(true)
+                .index(1),
+                )
+            )
+        .subcommand(
+            SubCommand::with_name("batch")
+            .about("Answers queries read from stdin, one per line, with one NDJSON result line each")
+            .after_help("QUERIES:
+    resolve <hash>                   The /ipfs/ path an IPFS/IPNS hash points at
+    index <hash>                     The whole index
+    refs <hash>                      The refs of the index
+    object <hash> <git_hash>         An object of the index
+
+Every result has the form {\"query\": ..., \"ok\": true, \"result\": ...} or
+{\"query\": ..., \"ok\": false, \"error\": ...}.")
+            )
+        .subcommand(
+            SubCommand::with_name("copy")
+            .about("Publishes an existing repo under another IPNS key, pinning its current generation locally")
+            .arg(
+                Arg::with_name("ipfs_hash")
+                .help("The IPFS/IPNS hash of the repo to copy")
+                .required(true)
+                .index(1),
+                )
+            .arg(
+                Arg::with_name("to_key")
+                .long("--to-key")
+                .value_name("KEY")
+                .required(true)
+                .help("The IPFS key to publish the copy under, e.g. one made with `ipfs key gen`"),
+                )
+            )
+        .subcommand(
+            SubCommand::with_name("ci-publish")
+            .about("Pushes HEAD of a local repo to a nip remote without asking anything and prints what was published")
+            .arg(
+                Arg::with_name("repo")
+                .long("--repo")
+                .value_name("PATH")
+                .help("The local repo to push from; Defaults to the current directory"),
+                )
             .arg(
-                Arg::with_name("json")
-                .short("j")
-                .long("--json")
-                .help("List the structure in JSON")
+                Arg::with_name("remote")
+                .long("--remote")
+                .value_name("NAME")
+                .help("The nip remote to push to; Defaults to origin"),
+                )
+            .arg(
+                Arg::with_name("ref")
+                .long("--ref")
+                .value_name("REF")
+                .help("The remote ref to push HEAD to; Defaults to the branch HEAD is on"),
+                )
+            )
+        .subcommand(
+            SubCommand::with_name("doctor")
+            .about("Diagnoses common problems with the environment nip runs in")
+            .arg(
+                Arg::with_name("repo")
+                .long("--repo")
+                .value_name("PATH")
+                .help("The local repo whose remotes should be checked; Defaults to the current directory"),
                 )
             )
             .get_matches();
 
-    let mut ipfs = IpfsClient::new("localhost", 5001).unwrap_or_else(|e| {
+    // Subcommands working with a particular remote take its remote.<name>.nip-* settings into
+    // account
+    let remote_name = cli_matches
+        .subcommand()
+        .1
+        .and_then(|matches| matches.value_of("remote"));
+    let config = match load_config(&cli_matches, remote_name) {
+        Ok(config) => {
+            init_logging(config.log_level);
+            config
+        }
+        Err(e) => {
+            init_logging(LevelFilter::Info);
+            error!("Could not load nip configuration: {}", e);
+            process::exit(1);
+        }
+    };
+
+    debug!("Using config {:#?}", config);
+
+    // doctor needs to run even if IPFS is unreachable
+    if let ("doctor", Some(matches)) = cli_matches.subcommand() {
+        handle_doctor(matches, &config);
+        return;
+    }
+
+    let mut ipfs = config.ipfs_client().unwrap_or_else(|e| {
         error!("Could not reach local IPFS instance: {}", e);
         process::exit(1);
     });
@@ -73,19 +566,14 @@ pub fn main() {
 
     debug!("IPFS connectivity OK. Datastore stats:\n{:#?}", stats);
 
+    let mut ipns_cache = match Repository::discover(".") {
+        Ok(repo) => IpnsCache::load(&repo, &config),
+        Err(_) => IpnsCache::in_memory(&config),
+    };
+
     match cli_matches.subcommand() {
         ("list", Some(matches)) => {
-            let nip_remote: NIPRemote = matches
-                .value_of("ipfs_hash")
-                .unwrap()
-                .replace("nip::", "")
-                .replace("nipdev::", "")
-                .parse()
-                .unwrap_or_else(|e: Error| {
-                    error!("{}", e);
-                    println!("{}", matches.usage());
-                    process::exit(1);
-                });
+            let nip_remote = parse_nip_remote_arg(matches, "ipfs_hash");
 
             debug!("Parsed link {}", nip_remote.to_string());
 
@@ -109,12 +597,217 @@ pub fn main() {
                 }
             }
         }
+        ("squash", Some(matches)) => {
+            let nip_remote = parse_nip_remote_arg(matches, "ipfs_hash");
+            handle_squash(&nip_remote, matches, &config, &mut ipns_cache, &mut ipfs);
+        }
+        ("refs", Some(matches)) => {
+            let nip_remote = parse_nip_remote_arg(matches, "ipfs_hash");
+            let idx = fetch_existing_index(&nip_remote, &mut ipns_cache, &mut ipfs);
+            handle_refs(&idx, matches);
+        }
+        ("diff-index", Some(matches)) => {
+            let nip_remote = parse_nip_remote_arg(matches, "ipfs_hash");
+            let idx = fetch_existing_index(&nip_remote, &mut ipns_cache, &mut ipfs);
+            handle_diff_index(&idx, matches);
+        }
+        ("reprovide", Some(matches)) => {
+            let nip_remote = parse_nip_remote_arg(matches, "ipfs_hash");
+            let idx = fetch_existing_index(&nip_remote, &mut ipns_cache, &mut ipfs);
+            handle_reprovide(
+                &idx,
+                &nip_remote,
+                matches.is_present("json"),
+                &mut ipns_cache,
+                &mut ipfs,
+            );
+        }
+        ("health", Some(matches)) => {
+            let nip_remote = parse_nip_remote_arg(matches, "ipfs_hash");
+            let idx = fetch_existing_index(&nip_remote, &mut ipns_cache, &mut ipfs);
+            handle_health(&idx, &nip_remote, matches, &config, &mut ipns_cache, &mut ipfs);
+        }
+        ("archive", Some(matches)) => {
+            let nip_remote = parse_nip_remote_arg(matches, "ipfs_hash");
+            let idx = fetch_existing_index(&nip_remote, &mut ipns_cache, &mut ipfs);
+            handle_archive(idx, matches, &mut ipfs);
+        }
+        ("snapshot", Some(matches)) => {
+            let nip_remote = parse_nip_remote_arg(matches, "ipfs_hash");
+            let idx = fetch_existing_index(&nip_remote, &mut ipns_cache, &mut ipfs);
+            handle_snapshot(idx, matches, &mut ipfs);
+        }
+        ("bundle", Some(matches)) => {
+            let nip_remote = parse_nip_remote_arg(matches, "ipfs_hash");
+            let idx = fetch_existing_index(&nip_remote, &mut ipns_cache, &mut ipfs);
+            handle_bundle(idx, matches, &mut ipfs);
+        }
+        ("export-index", Some(matches)) => {
+            let nip_remote = parse_nip_remote_arg(matches, "ipfs_hash");
+            let idx = fetch_existing_index(&nip_remote, &mut ipns_cache, &mut ipfs);
+            handle_export_index(&idx, matches);
+        }
+        ("import-index", Some(matches)) => {
+            handle_import_index(matches, &mut ipfs);
+        }
+        ("ref", Some(ref_matches)) => match ref_matches.subcommand() {
+            (action, Some(matches)) => {
+                let edit = match action {
+                    "set" => IndexEdit::SetRef(
+                        matches.value_of("ref").unwrap().to_owned(),
+                        matches.value_of("git_hash").unwrap().to_owned(),
+                    ),
+                    _ => IndexEdit::DeleteRef(matches.value_of("ref").unwrap().to_owned()),
+                };
+                let nip_remote = parse_nip_remote_arg(matches, "ipfs_hash");
+                let idx = fetch_existing_index(&nip_remote, &mut ipns_cache, &mut ipfs);
+                let json = matches.is_present("json");
+                handle_edit_index(
+                    idx,
+                    &nip_remote,
+                    edit,
+                    json,
+                    &config,
+                    &mut ipns_cache,
+                    &mut ipfs,
+                );
+            }
+            _ => error!("No ref subcommand specified. Run with -h for full usage."),
+        },
+        ("object", Some(object_matches)) => match object_matches.subcommand() {
+            ("get", Some(matches)) => {
+                let nip_remote = parse_nip_remote_arg(matches, "ipfs_hash");
+                let idx = fetch_existing_index(&nip_remote, &mut ipns_cache, &mut ipfs);
+                handle_object_get(&idx, matches, &mut ipfs);
+            }
+            ("drop", Some(matches)) => {
+                let edit = IndexEdit::DropObject(matches.value_of("git_hash").unwrap().to_owned());
+                let nip_remote = parse_nip_remote_arg(matches, "ipfs_hash");
+                let idx = fetch_existing_index(&nip_remote, &mut ipns_cache, &mut ipfs);
+                let json = matches.is_present("json");
+                handle_edit_index(
+                    idx,
+                    &nip_remote,
+                    edit,
+                    json,
+                    &config,
+                    &mut ipns_cache,
+                    &mut ipfs,
+                );
+            }
+            _ => error!("No object subcommand specified. Run with -h for full usage."),
+        },
+        ("reflog", Some(matches)) => {
+            let nip_remote = parse_nip_remote_arg(matches, "ipfs_hash");
+            handle_reflog(&nip_remote, matches, &mut ipns_cache, &mut ipfs);
+        }
+        ("du", Some(matches)) => {
+            let nip_remote = parse_nip_remote_arg(matches, "ipfs_hash");
+            let idx = fetch_existing_index(&nip_remote, &mut ipns_cache, &mut ipfs);
+            handle_du(&idx, matches.is_present("json"), &mut ipfs);
+        }
+        ("copy", Some(matches)) => {
+            let nip_remote = parse_nip_remote_arg(matches, "ipfs_hash");
+            let idx = fetch_existing_index(&nip_remote, &mut ipns_cache, &mut ipfs);
+            handle_copy(&idx, &nip_remote, matches, &config, &mut ipns_cache, &mut ipfs);
+        }
+        ("ci-publish", Some(matches)) => {
+            handle_ci_publish(matches, &mut ipns_cache, &mut ipfs);
+        }
+        ("batch", Some(_)) => {
+            handle_batch(&mut ipns_cache, &mut ipfs);
+        }
+        ("restore", Some(matches)) => {
+            let nip_remote = parse_nip_remote_arg(matches, "ipfs_hash");
+            let idx = fetch_existing_index(&nip_remote, &mut ipns_cache, &mut ipfs);
+            handle_restore(idx, &nip_remote, matches, &mut ipfs);
+        }
         _other => {
             error!("No subcommand specified. Run with -h for full usage.");
         }
     }
 }
 
+/// Resolves the nip configuration, with the top-level command line flags taking precedence. The
+/// git config layer comes from the repo in the current directory, if there is one, including the
+/// overrides for `remote_name`.
+fn load_config(matches: &ArgMatches, remote_name: Option<&str>) -> Result<NipConfig, Error> {
+    let git_config = match Repository::discover(".") {
+        Ok(repo) => repo.config().ok(),
+        Err(_) => Config::open_default().ok(),
+    };
+
+    let mut config = NipConfig::load(git_config.as_ref(), remote_name)?;
+
+    let cli_layer = PartialNipConfig {
+        api: matches.value_of("api").map(str::to_owned),
+        api_host: matches.value_of("api_host").map(str::to_owned),
+        api_port: matches
+            .value_of("api_port")
+            .map(|port| {
+                port.parse()
+                    .map_err(|e| format_err!("Invalid API port {:?}: {}", port, e))
+            })
+            .transpose()?,
+        ipns_cache_ttl: if matches.is_present("no_cache") {
+            Some(0)
+        } else {
+            None
+        },
+        log_level: matches.value_of("log_level").map(str::to_owned),
+        ..Default::default()
+    };
+    config.apply(&cli_layer)?;
+
+    Ok(config)
+}
+
+/// Parses a nip link passed as `arg_name`, stripping the `nip::`/`nipdev::` prefixes if present.
+fn parse_nip_remote_arg(matches: &ArgMatches, arg_name: &str) -> NIPRemote {
+    matches
+        .value_of(arg_name)
+        .unwrap()
+        .replace("nip::", "")
+        .replace("nipdev::", "")
+        .parse()
+        .unwrap_or_else(|e: Error| {
+            error!("{}", e);
+            println!("{}", matches.usage());
+            process::exit(1);
+        })
+}
+
+/// Downloads the index `nip_remote` points at, exiting if it can't be obtained.
+fn fetch_existing_index(
+    nip_remote: &NIPRemote,
+    ipns_cache: &mut IpnsCache,
+    ipfs: &mut IpfsClient,
+) -> NIPIndex {
+    let ipfs_hash = resolve_to_ipfs_path(nip_remote, ipns_cache, ipfs).unwrap_or_else(|e| {
+        error!("{}", e);
+        process::exit(1);
+    });
+
+    fetch_index(&ipfs_hash, ipfs).unwrap_or_else(|e| {
+        error!("Could not get index {} from IPFS: {}", ipfs_hash, e);
+        process::exit(1);
+    })
+}
+
+/// Pretty-prints `value` to stdout
+fn print_json(value: &serde_json::Value) {
+    println!("{}", serde_json::to_string_pretty(value).unwrap());
+}
+
+/// Opens the repo at the path given in the "repo" argument (or the current directory).
+fn open_repo_arg(matches: &ArgMatches) -> Repository {
+    let repo_path = matches.value_of("repo").unwrap_or(".");
+    Repository::discover(repo_path).unwrap_or_else(|e| {
+        error!("Could not open git repo at {}: {}", repo_path, e);
+        process::exit(1);
+    })
+}
+
 /// A helper that migrates an object and prints it.
 #[inline]
 fn migrate_and_handle_object(
@@ -178,16 +871,7 @@ fn handle_index(
 
         current_remote = new_remote;
 
-        let idx_bytes = ipfs_cat(&prev_idx_hash, ipfs).unwrap();
-        let version = parse_nip_header(idx_bytes.as_slice()).unwrap();
-
-        if version < NIP_PROTOCOL_VERSION {
-            info!(
-                "Migrating {}: version {} -> {}",
-                prev_idx_hash, version, NIP_PROTOCOL_VERSION
-            );
-        }
-        idx = migrate_index(&idx_bytes[NIP_HEADER_LEN..], version, ipfs).unwrap_or_else(|e| {
+        idx = fetch_index(&prev_idx_hash, ipfs).unwrap_or_else(|e| {
             error!("Could not get index {} from IPFS: {}", prev_idx_hash, e);
             process::exit(1);
         });
@@ -210,3 +894,1842 @@ fn handle_index(
         println!("{:#?}", idx);
     }
 }
+
+/// Rewrites the index chain starting at `nip_remote` so that at most `keep` previous generations
+/// are left behind the head.
+fn handle_squash(
+    nip_remote: &NIPRemote,
+    matches: &ArgMatches,
+    config: &NipConfig,
+    ipns_cache: &mut IpnsCache,
+    ipfs: &mut IpfsClient,
+) {
+    let keep_count: u32 = matches
+        .value_of("keep")
+        .map_or_else(|| Ok(0), |val| val.parse())
+        .unwrap_or_else(|e| {
+            error!("Could not parse keep count: {}", e);
+            process::exit(1);
+        });
+
+    // Pick the key up front, there's no point in squashing what we can't republish
+    let publish_key = match nip_remote {
+        NIPRemote::ExistingIPNS(_) => Some(find_publish_key(nip_remote, config, ipfs)),
+        _ => None,
+    };
+
+    // IPNS names are resolved first so that the walk only sees immutable generations
+    let head_hash = resolve_to_ipfs_path(nip_remote, ipns_cache, ipfs).unwrap_or_else(|e| {
+        error!("{}", e);
+        process::exit(1);
+    });
+
+    // Collect the generations to keep, head first
+    let mut chain = Vec::new();
+    let mut original_hashes = Vec::new();
+    let mut next_hash = Some(head_hash);
+    while let Some(hash) = next_hash {
+        // Past the kept generations we only need to keep walking for unpinning
+        if chain.len() as u32 > keep_count && !matches.is_present("unpin") {
+            break;
+        }
+
+        let idx = fetch_index(&hash, ipfs).unwrap_or_else(|e| {
+            error!("Could not get index {} from IPFS: {}", hash, e);
+            process::exit(1);
+        });
+
+        next_hash = idx.prev_idx_hash.clone();
+        original_hashes.push(hash);
+
+        if chain.len() as u32 <= keep_count {
+            chain.push(idx);
+        }
+    }
+
+    debug!(
+        "Keeping {} generation(s) out of the {} visited",
+        chain.len(),
+        original_hashes.len()
+    );
+
+    // Re-upload oldest first so that every generation can link to its rewritten predecessor
+    let mut prev_remote: Option<NIPRemote> = None;
+    let mut new_hashes = HashSet::new();
+    for mut idx in chain.into_iter().rev() {
+        if prev_remote.is_none() {
+            idx.prev_idx_hash = None;
+        }
+
+        let new_remote = idx.ipfs_add(ipfs, prev_remote.as_ref()).unwrap_or_else(|e| {
+            error!("Could not upload squashed index: {}", e);
+            process::exit(1);
+        });
+        debug!("Uploaded squashed generation {}", new_remote.to_string());
+
+        if let Some(new_hash) = new_remote.get_hash() {
+            new_hashes.insert(bare_ipfs_hash(&new_hash).to_owned());
+        }
+        prev_remote = Some(new_remote);
+    }
+
+    let new_head = prev_remote.unwrap().get_hash().unwrap();
+
+    if let Some(key) = publish_key {
+        publish_head(&new_head, &key, nip_remote, config, ipns_cache, ipfs);
+    }
+
+    if matches.is_present("unpin") {
+        let mut event_loop = Runtime::new().unwrap();
+        for hash in original_hashes {
+            // A generation that didn't change is uploaded as the very same block
+            if new_hashes.contains(bare_ipfs_hash(&hash)) {
+                debug!("Keeping {}, the squashed chain still uses it", hash);
+                continue;
+            }
+
+            match event_loop.block_on(ipfs.pin_rm(&hash, true)) {
+                Ok(_) => debug!("Unpinned {}", hash),
+                Err(e) => warn!("Could not unpin {}: {}", hash, e),
+            }
+        }
+    }
+
+    info!("Squashed index at {}", new_head);
+    if matches.is_present("json") {
+        print_json(&serde_json::json!({ "head": new_head }));
+    } else {
+        println!("{}", new_head);
+    }
+}
+
+/// Points the IPNS remote `nip_remote` at `new_head` using `key`, then updates `ipns_cache` to
+/// match. Exits on failure.
+fn publish_head(
+    new_head: &str,
+    key: &str,
+    nip_remote: &NIPRemote,
+    config: &NipConfig,
+    ipns_cache: &mut IpnsCache,
+    ipfs: &mut IpfsClient,
+) {
+    let mut event_loop = Runtime::new().unwrap();
+    let published = event_loop
+        .block_on(ipfs.name_publish(
+            new_head,
+            false,
+            config.ipns_lifetime.as_deref(),
+            config.ipns_ttl.as_deref(),
+            Some(key),
+        ))
+        .unwrap_or_else(|e| {
+            error!("Could not publish {} under key {}: {}", new_head, key, e);
+            process::exit(1);
+        });
+    let name = nip_remote.get_hash().unwrap();
+    ipns_cache.insert(&name, new_head);
+    info!("Published {} at /ipns/{} (key {})", new_head, published.name, key);
+}
+
+/// Finds the IPFS key the IPNS remote `nip_remote` is published under: the configured
+/// `ipns_key`, which has to match, or otherwise whichever local key it is. Exits if there's no
+/// such key, since publishing under another one would leave the remote untouched.
+fn find_publish_key(nip_remote: &NIPRemote, config: &NipConfig, ipfs: &mut IpfsClient) -> String {
+    let name = nip_remote.get_hash().unwrap();
+    let key_id = name.trim_start_matches("/ipns/");
+
+    let mut event_loop = Runtime::new().unwrap();
+    let key_list = event_loop.block_on(ipfs.key_list()).unwrap_or_else(|e| {
+        error!("Could not list IPNS keys: {}", e);
+        process::exit(1);
+    });
+    let matching_key = key_list.keys.iter().find(|key| key.id == key_id);
+
+    match (config.ipns_key.as_ref(), matching_key) {
+        (Some(configured), Some(key)) if *configured == key.name => key.name.clone(),
+        (Some(configured), Some(key)) => {
+            error!(
+                "{} belongs to key {}, not to the configured key {}",
+                name, key.name, configured
+            );
+            process::exit(1);
+        }
+        (None, Some(key)) => key.name.clone(),
+        (_, None) => {
+            error!("None of the local IPNS keys can publish {}", name);
+            process::exit(1);
+        }
+    }
+}
+
+/// Strips the `/ipfs/` prefix off `path`, making hashes from different sources comparable
+fn bare_ipfs_hash(path: &str) -> &str {
+    path.trim_start_matches("/ipfs/")
+}
+
+/// Walks the index chain `nip_remote` points at and prints every generation that changed the
+/// "ref" argument, along with the value it changed it to.
+fn handle_reflog(
+    nip_remote: &NIPRemote,
+    matches: &ArgMatches,
+    ipns_cache: &mut IpnsCache,
+    ipfs: &mut IpfsClient,
+) {
+    let name = matches.value_of("ref").unwrap();
+    let max_generations: Option<usize> = matches.value_of("max_generations").map(|val| {
+        val.parse().unwrap_or_else(|e| {
+            error!("Could not parse generation count: {}", e);
+            process::exit(1);
+        })
+    });
+
+    let candidates = [
+        name.to_owned(),
+        format!("refs/heads/{}", name),
+        format!("refs/tags/{}", name),
+    ];
+
+    // (index hash, the ref's value in that generation), newest first
+    let mut generations: Vec<(String, Option<String>)> = Vec::new();
+    let head_hash = resolve_to_ipfs_path(nip_remote, ipns_cache, ipfs).unwrap_or_else(|e| {
+        error!("{}", e);
+        process::exit(1);
+    });
+    let mut next_hash = Some(head_hash);
+    while let Some(hash) = next_hash {
+        if max_generations.map_or(false, |max| generations.len() >= max) {
+            break;
+        }
+
+        let idx = fetch_index(&hash, ipfs).unwrap_or_else(|e| {
+            error!("Could not get index {} from IPFS: {}", hash, e);
+            process::exit(1);
+        });
+
+        let value = candidates
+            .iter()
+            .find_map(|candidate| idx.refs.get(candidate))
+            .cloned();
+
+        next_hash = idx.prev_idx_hash;
+        generations.push((hash, value));
+    }
+
+    // A generation only counts if it differs from its predecessor; the oldest one visited is
+    // where the walk starts, so it counts if the ref exists there at all.
+    let mut entries = Vec::new();
+    for (i, (hash, value)) in generations.iter().enumerate() {
+        let changed = match generations.get(i + 1) {
+            Some((_, prev_value)) => value != prev_value,
+            None => value.is_some(),
+        };
+        if changed {
+            entries.push((hash, value));
+        }
+    }
+
+    if matches.is_present("json") {
+        let entries_json: Vec<_> = entries
+            .iter()
+            .map(|(hash, value)| serde_json::json!({ "index": hash, "git_hash": value }))
+            .collect();
+        print_json(&serde_json::json!(entries_json));
+        return;
+    }
+
+    if entries.is_empty() {
+        info!(
+            "{} doesn't appear in the {} generation(s) visited",
+            name,
+            generations.len()
+        );
+    }
+
+    for (hash, value) in entries {
+        match value {
+            Some(git_hash) => println!("{} {}", hash, git_hash),
+            None => println!("{} {}", hash, "(deleted)".red()),
+        }
+    }
+}
+
+/// Attributes the raw data of `idx`'s objects to its branches and tags. An object counts as
+/// unique to a ref if no other branch or tag reaches it and as shared otherwise.
+fn handle_du(idx: &NIPIndex, json: bool, ipfs: &mut IpfsClient) {
+    // git hash -> (raw data size, git hashes the object points at)
+    let mut objects: HashMap<String, (u64, Vec<String>)> = HashMap::new();
+    let mut reachable: Vec<(&String, HashSet<String>)> = Vec::new();
+
+    for (name, git_hash) in &idx.refs {
+        if !(name.starts_with("refs/heads/") || name.starts_with("refs/tags/")) {
+            continue;
+        }
+
+        let mut seen = HashSet::new();
+        let mut queue = vec![git_hash.clone()];
+        while let Some(git_hash) = queue.pop() {
+            if seen.contains(&git_hash) {
+                continue;
+            }
+
+            let obj_ipfs_hash = match idx.objects.get(&git_hash) {
+                Some(obj_ipfs_hash) if obj_ipfs_hash != NIP_SUBMODULE_TIP => obj_ipfs_hash,
+                _ => continue,
+            };
+
+            if !objects.contains_key(&git_hash) {
+                let entry = measure_object(obj_ipfs_hash, &git_hash, ipfs).unwrap_or_else(|e| {
+                    error!("Could not get object {} ({}): {}", git_hash, obj_ipfs_hash, e);
+                    process::exit(1);
+                });
+                debug!("{}: {} bytes", git_hash, entry.0);
+                objects.insert(git_hash.clone(), entry);
+            }
+
+            queue.extend(objects[&git_hash].1.iter().cloned());
+            seen.insert(git_hash);
+        }
+
+        reachable.push((name, seen));
+    }
+
+    let mut owners: HashMap<&str, usize> = HashMap::new();
+    for (_, seen) in &reachable {
+        for git_hash in seen {
+            *owners.entry(git_hash.as_str()).or_insert(0) += 1;
+        }
+    }
+
+    let size_of = |git_hash: &str| objects.get(git_hash).map_or(0, |(size, _)| *size);
+
+    // (ref, unique size, total size), biggest unique size first
+    let mut rows: Vec<(&String, u64, u64)> = reachable
+        .iter()
+        .map(|(name, seen)| {
+            let unique = seen
+                .iter()
+                .filter(|git_hash| owners[git_hash.as_str()] == 1)
+                .map(|git_hash| size_of(git_hash.as_str()))
+                .sum();
+            let total = seen.iter().map(|git_hash| size_of(git_hash.as_str())).sum();
+            (*name, unique, total)
+        })
+        .collect();
+    rows.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+
+    let shared: u64 = owners
+        .iter()
+        .filter(|(_, count)| **count > 1)
+        .map(|(git_hash, _)| size_of(git_hash))
+        .sum();
+    let total: u64 = objects.values().map(|(size, _)| size).sum();
+
+    if json {
+        let refs_json: Vec<_> = rows
+            .iter()
+            .map(|(name, unique, total)| {
+                serde_json::json!({ "ref": name, "unique": unique, "total": total })
+            })
+            .collect();
+        print_json(&serde_json::json!({
+            "refs": refs_json,
+            "shared": shared,
+            "total": total,
+        }));
+        return;
+    }
+
+    println!("{:>10} {:>10}  {}", "unique", "total", "ref");
+    for (name, unique, ref_total) in rows {
+        println!("{:>10} {:>10}  {}", format_size(unique), format_size(ref_total), name);
+    }
+    println!();
+    println!("{:>10}  shared by several refs", format_size(shared));
+    println!("{:>10}  reachable from any ref", format_size(total));
+}
+
+/// Downloads the raw data of the object at `ipfs_hash`, returning its size and the git hashes of
+/// the objects it points at
+fn measure_object(
+    ipfs_hash: &str,
+    git_hash: &str,
+    ipfs: &mut IpfsClient,
+) -> Result<(u64, Vec<String>), Error> {
+    let obj = fetch_object(ipfs_hash, git_hash, ipfs)?;
+    let data = ipfs_cat(&obj.raw_data_ipfs_hash, ipfs)?;
+
+    let children = match obj.metadata {
+        NIPObjectMetadata::Blob { .. } => Vec::new(),
+        NIPObjectMetadata::Commit { .. } | NIPObjectMetadata::Tag { .. } => {
+            header_children(&data)
+        }
+        NIPObjectMetadata::Tree { .. } => tree_children(&data)?,
+    };
+
+    Ok((data.len() as u64, children))
+}
+
+/// Picks the object hashes out of a raw commit or tag's header lines
+fn header_children(data: &[u8]) -> Vec<String> {
+    String::from_utf8_lossy(data)
+        .lines()
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| {
+            let mut words = line.splitn(2, ' ');
+            match (words.next(), words.next()) {
+                (Some("tree"), Some(hash))
+                | (Some("parent"), Some(hash))
+                | (Some("object"), Some(hash)) => Some(hash.to_owned()),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// Picks the entry hashes out of a raw tree, made of "<mode> <name>\0<20-byte hash>" entries.
+/// Submodule entries are left out, their commits live in other repos.
+fn tree_children(mut data: &[u8]) -> Result<Vec<String>, Error> {
+    let mut children = Vec::new();
+
+    while !data.is_empty() {
+        let nul = data
+            .iter()
+            .position(|byte| *byte == 0)
+            .ok_or_else(|| format_err!("Truncated tree entry"))?;
+        if data.len() < nul + 21 {
+            bail!("Truncated tree entry");
+        }
+
+        if !data.starts_with(b"160000 ") {
+            children.push(Oid::from_bytes(&data[nul + 1..nul + 21])?.to_string());
+        }
+        data = &data[nul + 21..];
+    }
+
+    Ok(children)
+}
+
+/// Writes `idx` as JSON to the "output" argument or stdout
+fn handle_export_index(idx: &NIPIndex, matches: &ArgMatches) {
+    let json = serde_json::to_string_pretty(idx).unwrap();
+
+    match matches.value_of("output") {
+        Some(output) => {
+            fs::write(output, json + "\n").unwrap_or_else(|e| {
+                error!("Could not write {}: {}", output, e);
+                process::exit(1);
+            });
+            info!(
+                "Exported {} ref(s) and {} object(s) to {}",
+                idx.refs.len(),
+                idx.objects.len(),
+                output
+            );
+            if matches.is_present("json") {
+                print_json(&serde_json::json!({
+                    "output": output,
+                    "refs": idx.refs.len(),
+                    "objects": idx.objects.len(),
+                }));
+            }
+        }
+        None => println!("{}", json),
+    }
+}
+
+/// Reads an index from the JSON file in the "file" argument and uploads it if requested
+fn handle_import_index(matches: &ArgMatches, ipfs: &mut IpfsClient) {
+    let file = matches.value_of("file").unwrap();
+
+    let mut idx: NIPIndex = fs::read_to_string(file)
+        .map_err(Error::from)
+        .and_then(|contents| Ok(serde_json::from_str(&contents)?))
+        .unwrap_or_else(|e| {
+            error!("Could not read an index from {}: {}", file, e);
+            process::exit(1);
+        });
+
+    info!(
+        "{} contains {} ref(s) and {} object(s)",
+        file,
+        idx.refs.len(),
+        idx.objects.len()
+    );
+
+    let new_hash = if matches.is_present("publish") {
+        let new_remote = idx.ipfs_add(ipfs, None).unwrap_or_else(|e| {
+            error!("Could not upload index: {}", e);
+            process::exit(1);
+        });
+        new_remote.get_hash()
+    } else {
+        None
+    };
+
+    if matches.is_present("json") {
+        print_json(&serde_json::json!({
+            "refs": idx.refs.len(),
+            "objects": idx.objects.len(),
+            "hash": new_hash,
+        }));
+    } else if let Some(ref new_hash) = new_hash {
+        info!("Imported index at {}", new_hash);
+        println!("{}", new_hash);
+    }
+}
+
+/// Writes the data of the object in the "git_hash" argument to the "output" argument
+fn handle_object_get(idx: &NIPIndex, matches: &ArgMatches, ipfs: &mut IpfsClient) {
+    let git_hash = matches.value_of("git_hash").unwrap();
+    let output = matches.value_of("output").unwrap();
+
+    let obj_ipfs_hash = match idx.objects.get(git_hash) {
+        Some(obj_ipfs_hash) if obj_ipfs_hash == NIP_SUBMODULE_TIP => {
+            error!("{} is a submodule commit, its data lives in another repo", git_hash);
+            process::exit(1);
+        }
+        Some(obj_ipfs_hash) => obj_ipfs_hash,
+        None => {
+            error!("{} is not an object of the index", git_hash);
+            process::exit(1);
+        }
+    };
+
+    let res = fetch_object(obj_ipfs_hash, git_hash, ipfs).and_then(|obj| {
+        let mut data = ipfs_cat(&obj.raw_data_ipfs_hash, ipfs)?;
+
+        if matches.is_present("with_header") {
+            let kind = match obj.metadata {
+                NIPObjectMetadata::Blob { .. } => "blob",
+                NIPObjectMetadata::Commit { .. } => "commit",
+                NIPObjectMetadata::Tag { .. } => "tag",
+                NIPObjectMetadata::Tree { .. } => "tree",
+            };
+            let mut with_header = format!("{} {}\0", kind, data.len()).into_bytes();
+            with_header.append(&mut data);
+            data = with_header;
+        }
+
+        fs::write(output, &data)?;
+        Ok(data.len())
+    });
+
+    match res {
+        Ok(len) => {
+            info!("Wrote {} ({} bytes) to {}", git_hash, len, output);
+            if matches.is_present("json") {
+                print_json(&serde_json::json!({
+                    "git_hash": git_hash,
+                    "bytes": len,
+                    "output": output,
+                }));
+            }
+        }
+        Err(e) => {
+            error!("Could not get object {}: {}", git_hash, e);
+            process::exit(1);
+        }
+    }
+}
+
+/// A single manual change to an index
+enum IndexEdit {
+    /// Point the ref at the git hash
+    SetRef(String, String),
+    DeleteRef(String),
+    /// Remove the object with the git hash from the object map
+    DropObject(String),
+}
+
+/// Applies `edit` to `idx` and uploads the result as the next generation after `nip_remote`,
+/// refusing to do so if the edit leaves the index inconsistent. IPNS remotes are republished
+/// under the key they already use.
+fn handle_edit_index(
+    mut idx: NIPIndex,
+    nip_remote: &NIPRemote,
+    edit: IndexEdit,
+    json: bool,
+    config: &NipConfig,
+    ipns_cache: &mut IpnsCache,
+    ipfs: &mut IpfsClient,
+) {
+    // Pick the key up front, there's no point in editing what we can't republish
+    let publish_key = match nip_remote {
+        NIPRemote::ExistingIPNS(_) => Some(find_publish_key(nip_remote, config, ipfs)),
+        _ => None,
+    };
+
+    match edit {
+        IndexEdit::SetRef(ref_name, git_hash) => {
+            if !ref_name.starts_with("refs/") {
+                error!("{} is not a full ref name, try refs/heads/{}", ref_name, ref_name);
+                process::exit(1);
+            }
+            match idx.refs.insert(ref_name.clone(), git_hash.clone()) {
+                Some(old_hash) => info!("Moving {}: {} -> {}", ref_name, old_hash, git_hash),
+                None => info!("Creating {} at {}", ref_name, git_hash),
+            }
+        }
+        IndexEdit::DeleteRef(ref_name) => match idx.refs.remove(&ref_name) {
+            Some(old_hash) => info!("Deleting {} (was {})", ref_name, old_hash),
+            None => {
+                error!("{} is not a ref of the index", ref_name);
+                process::exit(1);
+            }
+        },
+        IndexEdit::DropObject(git_hash) => match idx.objects.remove(&git_hash) {
+            Some(ipfs_hash) => info!("Dropping object {} ({})", git_hash, ipfs_hash),
+            None => {
+                error!("{} is not an object of the index", git_hash);
+                process::exit(1);
+            }
+        },
+    }
+
+    let problems = validate_index(&idx);
+    if !problems.is_empty() {
+        for problem in &problems {
+            error!("{}", problem);
+        }
+        error!("The edited index would be inconsistent, not uploading it");
+        process::exit(1);
+    }
+
+    // Link to the generation the name resolves to; nip_core would publish under its default key
+    // if handed the IPNS remote itself
+    let prev_remote: NIPRemote = resolve_to_ipfs_path(nip_remote, ipns_cache, ipfs)
+        .and_then(|prev_hash| prev_hash.parse())
+        .unwrap_or_else(|e| {
+            error!("{}", e);
+            process::exit(1);
+        });
+
+    let new_remote = idx.ipfs_add(ipfs, Some(&prev_remote)).unwrap_or_else(|e| {
+        error!("Could not upload edited index: {}", e);
+        process::exit(1);
+    });
+    let new_hash = new_remote.get_hash().unwrap();
+
+    if let Some(key) = publish_key {
+        publish_head(&new_hash, &key, nip_remote, config, ipns_cache, ipfs);
+    }
+
+    info!("Edited index at {}", new_hash);
+    if json {
+        print_json(&serde_json::json!({ "hash": new_hash }));
+    } else {
+        println!("{}", new_hash);
+    }
+}
+
+/// Lists the ways in which `idx` contradicts itself, i.e. refs whose tip can't be found through
+/// the object map.
+fn validate_index(idx: &NIPIndex) -> Vec<String> {
+    idx.refs
+        .iter()
+        .filter(|(_, git_hash)| match idx.objects.get(*git_hash) {
+            Some(ipfs_hash) => ipfs_hash == NIP_SUBMODULE_TIP,
+            None => true,
+        })
+        .map(|(ref_name, git_hash)| {
+            format!("{} points at {}, which the index has no object for", ref_name, git_hash)
+        })
+        .collect()
+}
+
+/// Prints how the refs of `idx` relate to their local counterparts
+fn handle_refs(idx: &NIPIndex, matches: &ArgMatches) {
+    let repo = open_repo_arg(matches);
+
+    let mut json_refs = Vec::new();
+
+    for (name, git_hash) in &idx.refs {
+        let (local_oid, counts) = compare_ref(&repo, name, git_hash).unwrap_or_else(|e| {
+            error!("Could not compare {}: {}", name, e);
+            process::exit(1);
+        });
+
+        if matches.is_present("json") {
+            json_refs.push(serde_json::json!({
+                "name": name,
+                "remote": git_hash,
+                "local": local_oid.map(|oid| oid.to_string()),
+                "ahead": counts.map(|(ahead, _)| ahead),
+                "behind": counts.map(|(_, behind)| behind),
+            }));
+            continue;
+        }
+
+        let status = match (local_oid, counts) {
+            (None, _) => "not present locally".to_owned(),
+            (Some(_), None) => "remote has commits missing locally, fetch to compare".to_owned(),
+            (Some(_), Some((0, 0))) => "up to date".to_owned(),
+            (Some(_), Some((ahead, behind))) => format!("{} ahead, {} behind", ahead, behind),
+        };
+        println!("{} {}: {}", git_hash, name, status);
+    }
+
+    // Local branches the remote doesn't know about
+    let remote_names: BTreeSet<&String> = idx.refs.keys().collect();
+    let branches = repo.branches(Some(BranchType::Local)).unwrap_or_else(|e| {
+        error!("Could not list local branches: {}", e);
+        process::exit(1);
+    });
+
+    for branch in branches {
+        let (branch, _) = branch.unwrap_or_else(|e| {
+            error!("Could not read local branch: {}", e);
+            process::exit(1);
+        });
+
+        let reference = branch.get();
+        let (name, target) = match (reference.name(), reference.target()) {
+            (Some(name), Some(target)) => (name.to_owned(), target),
+            _ => continue,
+        };
+
+        if remote_names.contains(&name) {
+            continue;
+        }
+
+        if matches.is_present("json") {
+            json_refs.push(serde_json::json!({
+                "name": name,
+                "remote": null,
+                "local": target.to_string(),
+                "ahead": null,
+                "behind": null,
+            }));
+        } else {
+            println!("{} {}: not present on the remote", target, name);
+        }
+    }
+
+    if matches.is_present("json") {
+        println!("{}", serde_json::to_string_pretty(&json_refs).unwrap());
+    }
+}
+
+/// Looks up the local counterpart of remote ref `name` and counts how many commits it is
+/// ahead/behind `remote_hash`. The counts are `None` if the remote commit isn't available locally.
+fn compare_ref(
+    repo: &Repository,
+    name: &str,
+    remote_hash: &str,
+) -> Result<(Option<Oid>, Option<(usize, usize)>), Error> {
+    let local_oid = match repo.find_reference(name) {
+        Ok(reference) => reference.peel(ObjectType::Commit)?.id(),
+        Err(_) => return Ok((None, None)),
+    };
+
+    // The remote ref may point at an annotated tag
+    let remote_oid = Oid::from_str(remote_hash)?;
+    let remote_commit_oid = match repo
+        .find_object(remote_oid, None)
+        .and_then(|obj| obj.peel(ObjectType::Commit))
+    {
+        Ok(commit) => commit.id(),
+        Err(_) => return Ok((Some(local_oid), None)),
+    };
+
+    let counts = repo.graph_ahead_behind(local_oid, remote_commit_oid)?;
+
+    Ok((Some(local_oid), Some(counts)))
+}
+
+/// Prints the objects only one of `idx` and the local repo has
+fn handle_diff_index(idx: &NIPIndex, matches: &ArgMatches) {
+    let repo = open_repo_arg(matches);
+
+    let local_objects = collect_local_objects(&repo).unwrap_or_else(|e| {
+        error!("Could not enumerate local objects: {}", e);
+        process::exit(1);
+    });
+
+    let odb = repo.odb().unwrap_or_else(|e| {
+        error!("Could not open the local object database: {}", e);
+        process::exit(1);
+    });
+
+    let missing_remotely: BTreeSet<String> = local_objects
+        .iter()
+        .map(|oid| oid.to_string())
+        .filter(|git_hash| !idx.objects.contains_key(git_hash))
+        .collect();
+
+    let missing_locally: BTreeSet<String> = idx
+        .objects
+        .iter()
+        .filter(|(_, ipfs_hash)| ipfs_hash.as_str() != NIP_SUBMODULE_TIP)
+        .map(|(git_hash, _)| git_hash)
+        .filter(|git_hash| match Oid::from_str(git_hash) {
+            Ok(oid) => !odb.exists(oid),
+            Err(_) => true,
+        })
+        .cloned()
+        .collect();
+
+    if matches.is_present("json") {
+        let json = serde_json::json!({
+            "missing_remotely": missing_remotely,
+            "missing_locally": missing_locally,
+        });
+        println!("{}", serde_json::to_string_pretty(&json).unwrap());
+        return;
+    }
+
+    println!("Missing from the remote index ({}):", missing_remotely.len());
+    for git_hash in &missing_remotely {
+        println!("{}", git_hash);
+    }
+
+    println!("Missing from the local repo ({}):", missing_locally.len());
+    for git_hash in &missing_locally {
+        println!("{}", git_hash);
+    }
+}
+
+/// Collects every object reachable from the local branches and tags, skipping submodule tips.
+fn collect_local_objects(repo: &Repository) -> Result<HashSet<Oid>, Error> {
+    let mut objects = HashSet::new();
+    let mut revwalk = repo.revwalk()?;
+
+    for reference in repo.references()? {
+        let reference = reference?;
+        if !(reference.is_branch() || reference.is_tag()) {
+            continue;
+        }
+
+        // Peeling would skip right past annotated tags, so their chains are followed by hand.
+        // They end at a commit, or occasionally at some other object.
+        let target = reference
+            .resolve()?
+            .target()
+            .ok_or_else(|| format_err!("{} has no target", reference.name().unwrap_or("?")))?;
+        let mut obj = repo.find_object(target, None)?;
+        while obj.kind() == Some(ObjectType::Tag) {
+            objects.insert(obj.id());
+            let target = obj
+                .as_tag()
+                .ok_or_else(|| format_err!("{} is not a tag", obj.id()))?
+                .target()?;
+            obj = target;
+        }
+
+        match obj.kind() {
+            Some(ObjectType::Commit) => revwalk.push(obj.id())?,
+            Some(ObjectType::Tree) => {
+                collect_tree_objects(&repo.find_tree(obj.id())?, &mut objects)?
+            }
+            _ => {
+                objects.insert(obj.id());
+            }
+        }
+    }
+
+    for commit_oid in revwalk {
+        let commit = repo.find_commit(commit_oid?)?;
+        objects.insert(commit.id());
+        collect_tree_objects(&commit.tree()?, &mut objects)?;
+    }
+
+    Ok(objects)
+}
+
+/// Adds `tree` and everything beneath it to `objects`, skipping submodule tips.
+fn collect_tree_objects(tree: &git2::Tree, objects: &mut HashSet<Oid>) -> Result<(), Error> {
+    if !objects.insert(tree.id()) {
+        return Ok(());
+    }
+
+    tree.walk(TreeWalkMode::PreOrder, |_, entry| {
+        if entry.kind() == Some(ObjectType::Commit) || !objects.insert(entry.id()) {
+            return TreeWalkResult::Skip;
+        }
+        TreeWalkResult::Ok
+    })?;
+
+    Ok(())
+}
+
+/// Lists the block roots making up the repo at `nip_remote` - the index, every object and every
+/// object's raw data - each with a human-readable description.
+fn collect_repo_blocks(
+    idx: &NIPIndex,
+    nip_remote: &NIPRemote,
+    ipns_cache: &mut IpnsCache,
+    ipfs: &mut IpfsClient,
+) -> Result<Vec<(String, String)>, Error> {
+    let idx_path = resolve_to_ipfs_path(nip_remote, ipns_cache, ipfs)?;
+
+    let mut blocks = vec![("index".to_owned(), idx_path)];
+
+    for (git_hash, obj_ipfs_hash) in &idx.objects {
+        if obj_ipfs_hash == NIP_SUBMODULE_TIP {
+            continue;
+        }
+
+        let obj = fetch_object(obj_ipfs_hash, git_hash, ipfs).map_err(|e| {
+            format_err!("Could not get object {} ({}): {}", git_hash, obj_ipfs_hash, e)
+        })?;
+
+        blocks.push((format!("object {}", git_hash), obj_ipfs_hash.clone()));
+        blocks.push((format!("raw data of {}", git_hash), obj.raw_data_ipfs_hash));
+    }
+
+    Ok(blocks)
+}
+
+/// Explicitly announces every block root of the repo at `nip_remote` via the DHT
+fn handle_reprovide(
+    idx: &NIPIndex,
+    nip_remote: &NIPRemote,
+    json: bool,
+    ipns_cache: &mut IpnsCache,
+    ipfs: &mut IpfsClient,
+) {
+    let to_provide = collect_repo_blocks(idx, nip_remote, ipns_cache, ipfs).unwrap_or_else(|e| {
+        error!("{}", e);
+        process::exit(1);
+    });
+
+    let mut event_loop = Runtime::new().unwrap();
+    let mut failed = Vec::new();
+    let total = to_provide.len();
+
+    for (i, (_, path)) in to_provide.iter().enumerate() {
+        let cid = path.trim_start_matches("/ipfs/");
+
+        match event_loop.block_on(ipfs.dht_provide(cid).collect()) {
+            Ok(_) => debug!("[{}/{}] Provided {}", i + 1, total, cid),
+            Err(e) => {
+                warn!("[{}/{}] Could not provide {}: {}", i + 1, total, cid, e);
+                failed.push(cid);
+            }
+        }
+    }
+
+    if json {
+        print_json(&serde_json::json!({
+            "provided": total - failed.len(),
+            "failed": failed,
+        }));
+    }
+
+    if !failed.is_empty() {
+        error!("{} out of {} blocks could not be provided", failed.len(), total);
+        process::exit(1);
+    }
+
+    info!("Provided {} blocks", total);
+}
+
+/// Checks whether the blocks of the repo at `nip_remote` can be obtained from the network rather
+/// than just the local datastore by retrieving them through a gateway. With `--providers-only` a
+/// block merely has to be announced by another peer in the DHT.
+fn handle_health(
+    idx: &NIPIndex,
+    nip_remote: &NIPRemote,
+    matches: &ArgMatches,
+    config: &NipConfig,
+    ipns_cache: &mut IpnsCache,
+    ipfs: &mut IpfsClient,
+) {
+    let sample_size: Option<usize> = matches.value_of("sample").map(|val| {
+        val.parse().unwrap_or_else(|e| {
+            error!("Could not parse sample size: {}", e);
+            process::exit(1);
+        })
+    });
+
+    let timeout = Duration::from_secs(
+        matches
+            .value_of("timeout")
+            .map_or_else(|| Ok(config.timeout), |val| val.parse())
+            .unwrap_or_else(|e| {
+                error!("Could not parse timeout: {}", e);
+                process::exit(1);
+            }),
+    );
+
+    let mut blocks = collect_repo_blocks(idx, nip_remote, ipns_cache, ipfs).unwrap_or_else(|e| {
+        error!("{}", e);
+        process::exit(1);
+    });
+
+    // Spread the sample evenly over the repo
+    if let Some(sample_size) = sample_size {
+        if sample_size > 0 && sample_size < blocks.len() {
+            let stride = blocks.len() / sample_size;
+            blocks = blocks.into_iter().step_by(stride).take(sample_size).collect();
+        }
+    }
+
+    let mut event_loop = Runtime::new().unwrap();
+    let gateway = if matches.is_present("providers_only") {
+        None
+    } else {
+        Some(
+            matches
+                .value_of("via_gateway")
+                .unwrap_or(DEFAULT_HEALTH_GATEWAY),
+        )
+    };
+
+    let own_id = event_loop
+        .block_on(ipfs.id(None))
+        .unwrap_or_else(|e| {
+            error!("Could not get the local peer ID: {}", e);
+            process::exit(1);
+        })
+        .id;
+
+    let gateway_client = gateway.map(|_| {
+        let https = HttpsConnector::new(4).unwrap_or_else(|e| {
+            error!("Could not set up HTTPS: {}", e);
+            process::exit(1);
+        });
+        Client::builder().build::<_, Body>(https)
+    });
+
+    let total = blocks.len();
+    let mut unavailable = Vec::new();
+
+    for (i, (description, path)) in blocks.iter().enumerate() {
+        let cid = path.trim_start_matches("/ipfs/");
+
+        let available = match (gateway, &gateway_client) {
+            (Some(gateway), Some(client)) => {
+                check_via_gateway(gateway, cid, client, timeout, &mut event_loop)
+            }
+            _ => check_via_dht(cid, &own_id, timeout, ipfs, &mut event_loop),
+        };
+
+        match available {
+            Ok(true) => debug!("[{}/{}] {} ({}) is available", i + 1, total, description, cid),
+            Ok(false) => {
+                warn!("[{}/{}] {} ({}) is NOT available", i + 1, total, description, cid);
+                unavailable.push((description, cid));
+            }
+            Err(e) => {
+                warn!("[{}/{}] Could not check {} ({}): {}", i + 1, total, description, cid, e);
+                unavailable.push((description, cid));
+            }
+        }
+    }
+
+    if matches.is_present("json") {
+        let unavailable_json: Vec<_> = unavailable
+            .iter()
+            .map(|(description, cid)| serde_json::json!({ "cid": cid, "description": description }))
+            .collect();
+        print_json(&serde_json::json!({
+            "checked": total,
+            "unavailable": unavailable_json,
+        }));
+    }
+
+    // Without a gateway we only know that someone claims to have the blocks
+    let state = if gateway.is_some() {
+        "retrievable"
+    } else {
+        "announced by other peers"
+    };
+
+    if !unavailable.is_empty() {
+        error!("{} out of {} blocks are not {}:", unavailable.len(), total, state);
+        if !matches.is_present("json") {
+            for (description, cid) in unavailable {
+                println!("{} {}", cid, description);
+            }
+        }
+        process::exit(1);
+    }
+
+    info!("All {} checked blocks are {}", total, state);
+}
+
+/// Asks the DHT for a provider of `cid` other than `own_id`
+fn check_via_dht(
+    cid: &str,
+    own_id: &str,
+    timeout: Duration,
+    ipfs: &mut IpfsClient,
+    event_loop: &mut Runtime,
+) -> Result<bool, Error> {
+    let own_id = own_id.to_owned();
+    let other_provider = ipfs
+        .dht_findprovs(cid)
+        .filter(move |msg| match msg.typ {
+            DhtType::Provider => msg.responses.iter().any(|peer| peer.id != own_id),
+            _ => false,
+        })
+        .into_future()
+        .map_err(|(e, _)| format_err!("{}", e))
+        .timeout(timeout);
+
+    match event_loop.block_on(other_provider) {
+        Ok((found, _)) => Ok(found.is_some()),
+        Err(ref e) if e.is_elapsed() => Ok(false),
+        Err(e) => Err(format_err!("{}", e)),
+    }
+}
+
+/// Downloads `cid` through `gateway`, telling whether the whole block arrived in time
+fn check_via_gateway(
+    gateway: &str,
+    cid: &str,
+    client: &Client<HttpsConnector<HttpConnector>>,
+    timeout: Duration,
+    event_loop: &mut Runtime,
+) -> Result<bool, Error> {
+    let uri: Uri = format!("{}/ipfs/{}", gateway.trim_end_matches('/'), cid).parse()?;
+    let req = Request::get(uri).body(Body::empty())?;
+
+    // Gateways may answer before they have the content, only a complete body shows it's there
+    let retrieval = client
+        .request(req)
+        .and_then(|res| {
+            let success = res.status().is_success();
+            res.into_body().concat2().map(move |_| success)
+        })
+        .timeout(timeout);
+
+    match event_loop.block_on(retrieval) {
+        Ok(success) => Ok(success),
+        Err(ref e) if e.is_elapsed() => Ok(false),
+        Err(e) => Err(format_err!("{}", e)),
+    }
+}
+
+/// Initializes a repository in the "dir" argument and fills it with every ref of `idx`, then
+/// checks out the default branch. The nip remote is added as `origin`.
+fn handle_restore(
+    mut idx: NIPIndex,
+    nip_remote: &NIPRemote,
+    matches: &ArgMatches,
+    ipfs: &mut IpfsClient,
+) {
+    let dir = Path::new(matches.value_of("dir").unwrap());
+
+    let not_empty = fs::read_dir(dir)
+        .map(|mut entries| entries.next().is_some())
+        .unwrap_or(false);
+    if not_empty {
+        error!("{} already exists and is not empty", dir.display());
+        process::exit(1);
+    }
+
+    let mut repo = Repository::init(dir).unwrap_or_else(|e| {
+        error!("Could not create a repository at {}: {}", dir.display(), e);
+        process::exit(1);
+    });
+
+    let default_branch = restore_refs(&mut idx, nip_remote, &mut repo, ipfs).unwrap_or_else(|e| {
+        error!("Could not restore {}: {}", nip_remote.to_string(), e);
+        process::exit(1);
+    });
+
+    match default_branch {
+        Some(ref branch) => {
+            let res = repo.set_head(branch).and_then(|_| {
+                repo.checkout_head(Some(git2::build::CheckoutBuilder::new().force()))
+            });
+            if let Err(e) = res {
+                error!("Could not check out {}: {}", branch, e);
+                process::exit(1);
+            }
+            info!(
+                "Restored {} into {}, checked out {}",
+                nip_remote.to_string(),
+                dir.display(),
+                branch
+            );
+        }
+        None => warn!(
+            "Restored {} into {}, but there's no branch to check out",
+            nip_remote.to_string(),
+            dir.display()
+        ),
+    }
+
+    if matches.is_present("json") {
+        print_json(&serde_json::json!({
+            "dir": dir.display().to_string(),
+            "refs": idx.refs.len(),
+            "branch": default_branch,
+        }));
+    }
+}
+
+/// Downloads every ref of `idx` into `repo` and creates it there, then registers `nip_remote` as
+/// `origin`. Returns the branch that should be checked out, preferring "master".
+fn restore_refs(
+    idx: &mut NIPIndex,
+    nip_remote: &NIPRemote,
+    repo: &mut Repository,
+    ipfs: &mut IpfsClient,
+) -> Result<Option<String>, Error> {
+    let refs = idx.refs.clone();
+
+    for (ref_name, git_hash) in refs.iter().filter(|(name, _)| name.starts_with("refs/")) {
+        fetch_ref_into(idx, git_hash, ref_name, repo, ipfs)?;
+    }
+
+    let ipfs_hash = nip_remote
+        .get_hash()
+        .ok_or_else(|| format_err!("{} is not an existing nip repo", nip_remote.to_string()))?;
+    repo.remote("origin", &format!("nip::{}", ipfs_hash))?;
+
+    let master = "refs/heads/master";
+    if refs.contains_key(master) {
+        return Ok(Some(master.to_owned()));
+    }
+
+    Ok(refs
+        .keys()
+        .find(|name| name.starts_with("refs/heads/"))
+        .cloned())
+}
+
+/// Downloads `git_hash` and everything it references from `idx` into `repo`, pointing `ref_name`
+/// at it.
+fn fetch_ref_into(
+    idx: &mut NIPIndex,
+    git_hash: &str,
+    ref_name: &str,
+    repo: &mut Repository,
+    ipfs: &mut IpfsClient,
+) -> Result<(), Error> {
+    info!("Fetching {} ({})", ref_name, git_hash);
+    idx.fetch_to_ref_from_str(git_hash, ref_name, repo, ipfs)?;
+    repo.reference(ref_name, Oid::from_str(git_hash)?, true, "nipctl")?;
+    Ok(())
+}
+
+/// A throwaway bare repository in the system's temporary directory, deleted on drop
+struct ScratchRepo {
+    repo: Repository,
+}
+
+impl ScratchRepo {
+    fn new(purpose: &str) -> Result<Self, Error> {
+        let path = env::temp_dir().join(format!("nipctl-{}-{}", purpose, process::id()));
+        if path.exists() {
+            fs::remove_dir_all(&path)?;
+        }
+
+        debug!("Creating scratch repo at {}", path.display());
+        Ok(Self {
+            repo: Repository::init_bare(&path)?,
+        })
+    }
+
+    /// Runs git with `args` in the scratch repo
+    fn git(&self, args: &[&str]) -> Result<(), Error> {
+        let status = process::Command::new("git")
+            .arg("--git-dir")
+            .arg(self.repo.path())
+            .args(args)
+            .status()
+            .map_err(|e| format_err!("Could not run git: {}", e))?;
+
+        if !status.success() {
+            bail!("git {} failed ({})", args.join(" "), status);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ScratchRepo {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_dir_all(self.repo.path()) {
+            warn!("Could not remove {}: {}", self.repo.path().display(), e);
+        }
+    }
+}
+
+/// Finds the ref of `idx` that `name` refers to the way git would, i.e. trying it as a full ref
+/// name, a branch and a tag in that order. A commit hash the index has an object for is
+/// accepted too. Returns a full ref name and the git hash it points at.
+fn find_index_ref(idx: &NIPIndex, name: &str) -> Option<(String, String)> {
+    let candidates = [
+        name.to_owned(),
+        format!("refs/heads/{}", name),
+        format!("refs/tags/{}", name),
+    ];
+
+    for candidate in &candidates {
+        if let Some(git_hash) = idx.refs.get(candidate) {
+            return Some((candidate.clone(), git_hash.clone()));
+        }
+    }
+
+    if idx.objects.contains_key(name) {
+        return Some((format!("refs/nipctl/{}", name), name.to_owned()));
+    }
+
+    None
+}
+
+/// Reconstructs the commit named by the "ref" argument in a scratch repo and has `git archive`
+/// write its tree to the "output" argument.
+fn handle_archive(mut idx: NIPIndex, matches: &ArgMatches, ipfs: &mut IpfsClient) {
+    let name = matches.value_of("ref").unwrap();
+    let output = matches.value_of("output").unwrap();
+
+    let (ref_name, git_hash) = find_index_ref(&idx, name).unwrap_or_else(|| {
+        error!("{} is neither a ref nor an object of the index", name);
+        process::exit(1);
+    });
+
+    let res = ScratchRepo::new("archive").and_then(|mut scratch| {
+        fetch_ref_into(&mut idx, &git_hash, &ref_name, &mut scratch.repo, ipfs)?;
+        scratch.git(&["archive", "--output", output, &ref_name])
+    });
+
+    match res {
+        Ok(()) => {
+            info!("Wrote {} ({}) to {}", ref_name, git_hash, output);
+            if matches.is_present("json") {
+                print_json(&serde_json::json!({
+                    "ref": ref_name,
+                    "git_hash": git_hash,
+                    "output": output,
+                }));
+            }
+        }
+        Err(e) => {
+            error!("Could not archive {}: {}", name, e);
+            process::exit(1);
+        }
+    }
+}
+
+/// Assembles the tree of the "ref" argument in MFS and pins the resulting directory. The MFS copy
+/// is removed afterwards, the pin is what keeps the snapshot around.
+fn handle_snapshot(mut idx: NIPIndex, matches: &ArgMatches, ipfs: &mut IpfsClient) {
+    let name = matches.value_of("ref").unwrap();
+
+    let (ref_name, git_hash) = find_index_ref(&idx, name).unwrap_or_else(|| {
+        error!("{} is neither a ref nor an object of the index", name);
+        process::exit(1);
+    });
+
+    let mfs_dir = format!("/nipctl-snapshot-{}", process::id());
+    let res = ScratchRepo::new("snapshot").and_then(|mut scratch| {
+        fetch_ref_into(&mut idx, &git_hash, &ref_name, &mut scratch.repo, ipfs)?;
+        // Annotated tags are peeled down to the tree as well
+        let tree_oid = scratch
+            .repo
+            .find_object(Oid::from_str(&git_hash)?, None)?
+            .peel(ObjectType::Tree)?
+            .id();
+        let tree = scratch.repo.find_tree(tree_oid)?;
+
+        let snapshot_hash = nip::mfs::write_tree(&scratch.repo, &tree, &idx, &mfs_dir, ipfs)?;
+
+        let mut event_loop = Runtime::new()?;
+        event_loop
+            .block_on(ipfs.pin_add(&snapshot_hash, true))
+            .map_err(|e| format_err!("Could not pin {}: {}", snapshot_hash, e))?;
+        if let Err(e) = event_loop.block_on(ipfs.files_rm(&mfs_dir, true)) {
+            warn!("Could not remove {} from MFS: {}", mfs_dir, e);
+        }
+
+        Ok(snapshot_hash)
+    });
+
+    match res {
+        Ok(snapshot_hash) => {
+            info!("Published the tree of {} ({}) as {}", ref_name, git_hash, snapshot_hash);
+            if matches.is_present("json") {
+                print_json(&serde_json::json!({
+                    "ref": ref_name,
+                    "git_hash": git_hash,
+                    "snapshot": format!("/ipfs/{}", snapshot_hash),
+                }));
+            } else {
+                println!("/ipfs/{}", snapshot_hash);
+            }
+        }
+        Err(e) => {
+            error!("Could not snapshot {}: {}", name, e);
+            process::exit(1);
+        }
+    }
+}
+
+/// Reconstructs the refs named by the "ref" arguments in a scratch repo and has `git bundle`
+/// write them to the "output" argument. The first branch becomes the bundle's HEAD so that
+/// cloning the bundle checks it out.
+fn handle_bundle(mut idx: NIPIndex, matches: &ArgMatches, ipfs: &mut IpfsClient) {
+    let output = matches.value_of("output").unwrap();
+
+    let refs: Vec<(String, String)> = matches
+        .values_of("ref")
+        .unwrap()
+        .map(|name| {
+            find_index_ref(&idx, name).unwrap_or_else(|| {
+                error!("{} is neither a ref nor an object of the index", name);
+                process::exit(1);
+            })
+        })
+        .collect();
+
+    let res = ScratchRepo::new("bundle").and_then(|mut scratch| {
+        for (ref_name, git_hash) in &refs {
+            fetch_ref_into(&mut idx, git_hash, ref_name, &mut scratch.repo, ipfs)?;
+        }
+
+        let mut args = vec!["bundle", "create", output];
+        args.extend(refs.iter().map(|(ref_name, _)| ref_name.as_str()));
+
+        if let Some((branch, _)) = refs.iter().find(|(name, _)| name.starts_with("refs/heads/")) {
+            scratch.repo.set_head(branch)?;
+            args.push("HEAD");
+        }
+
+        scratch.git(&args)
+    });
+
+    match res {
+        Ok(()) => {
+            info!("Wrote {} ref(s) to {}", refs.len(), output);
+            if matches.is_present("json") {
+                let ref_names: Vec<_> = refs.iter().map(|(ref_name, _)| ref_name).collect();
+                print_json(&serde_json::json!({ "refs": ref_names, "output": output }));
+            }
+        }
+        Err(e) => {
+            error!("Could not create bundle: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+/// Runs the queries on stdin until EOF, printing their results as they complete
+fn handle_batch(ipns_cache: &mut IpnsCache, ipfs: &mut IpfsClient) {
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+
+    for line in stdin.lock().lines() {
+        let line = line.unwrap_or_else(|e| {
+            error!("Could not read stdin: {}", e);
+            process::exit(1);
+        });
+        let query = line.trim();
+        if query.is_empty() {
+            continue;
+        }
+
+        let response = match run_batch_query(query, ipns_cache, ipfs) {
+            Ok(result) => serde_json::json!({ "query": query, "ok": true, "result": result }),
+            Err(e) => serde_json::json!({ "query": query, "ok": false, "error": e.to_string() }),
+        };
+
+        // Flush every result so that the other end can react before sending more queries
+        let res = writeln!(out, "{}", response).and_then(|_| out.flush());
+        if let Err(e) = res {
+            error!("Could not write to stdout: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+/// Answers a single `nipctl batch` query
+fn run_batch_query(
+    query: &str,
+    ipns_cache: &mut IpnsCache,
+    ipfs: &mut IpfsClient,
+) -> Result<serde_json::Value, Error> {
+    let words: Vec<&str> = query.split_whitespace().collect();
+
+    let parse_remote = |hash: &str| -> Result<NIPRemote, Error> {
+        hash.replace("nip::", "").replace("nipdev::", "").parse()
+    };
+
+    match words.as_slice() {
+        ["resolve", hash] => {
+            let path = resolve_to_ipfs_path(&parse_remote(hash)?, ipns_cache, ipfs)?;
+            Ok(serde_json::json!({ "path": path }))
+        }
+        ["index", hash] | ["refs", hash] => {
+            let path = resolve_to_ipfs_path(&parse_remote(hash)?, ipns_cache, ipfs)?;
+            let idx = fetch_index(&path, ipfs)?;
+            if words[0] == "refs" {
+                Ok(serde_json::to_value(&idx.refs)?)
+            } else {
+                Ok(serde_json::to_value(&idx)?)
+            }
+        }
+        ["object", hash, git_hash] => {
+            let path = resolve_to_ipfs_path(&parse_remote(hash)?, ipns_cache, ipfs)?;
+            let idx = fetch_index(&path, ipfs)?;
+            let obj_ipfs_hash = idx
+                .objects
+                .get(*git_hash)
+                .ok_or_else(|| format_err!("{} is not an object of the index", git_hash))?;
+            if obj_ipfs_hash == NIP_SUBMODULE_TIP {
+                bail!("{} is a submodule commit", git_hash);
+            }
+            Ok(serde_json::to_value(&fetch_object(obj_ipfs_hash, git_hash, ipfs)?)?)
+        }
+        [command, ..] => bail!("Unknown query {:?} or wrong number of arguments", command),
+        [] => bail!("Empty query"),
+    }
+}
+
+/// Pins every block of the current generation of the repo at `nip_remote` and publishes its
+/// index under the "to_key" argument, giving the repo a second, independent IPNS address.
+fn handle_copy(
+    idx: &NIPIndex,
+    nip_remote: &NIPRemote,
+    matches: &ArgMatches,
+    config: &NipConfig,
+    ipns_cache: &mut IpnsCache,
+    ipfs: &mut IpfsClient,
+) {
+    let key = matches.value_of("to_key").unwrap();
+
+    let blocks = collect_repo_blocks(idx, nip_remote, ipns_cache, ipfs).unwrap_or_else(|e| {
+        error!("{}", e);
+        process::exit(1);
+    });
+    // The index is the first block
+    let idx_path = blocks[0].1.clone();
+
+    // The copy must survive the original being unpinned elsewhere
+    let mut event_loop = Runtime::new().unwrap();
+    let total = blocks.len();
+    for (i, (description, path)) in blocks.iter().enumerate() {
+        event_loop
+            .block_on(ipfs.pin_add(path, true))
+            .unwrap_or_else(|e| {
+                error!("Could not pin {} ({}): {}", description, path, e);
+                process::exit(1);
+            });
+        debug!("[{}/{}] Pinned {}", i + 1, total, path);
+    }
+
+    let published = event_loop
+        .block_on(ipfs.name_publish(
+            &idx_path,
+            false,
+            config.ipns_lifetime.as_deref(),
+            config.ipns_ttl.as_deref(),
+            Some(key),
+        ))
+        .unwrap_or_else(|e| {
+            error!("Could not publish {} under key {}: {}", idx_path, key, e);
+            process::exit(1);
+        });
+    let name = format!("/ipns/{}", published.name);
+    ipns_cache.insert(&name, &idx_path);
+
+    info!("Published {} at {} (key {})", idx_path, name, key);
+    if matches.is_present("json") {
+        print_json(&serde_json::json!({
+            "index": idx_path,
+            "ipns": name,
+            "pinned": total,
+        }));
+    } else {
+        println!("nip::{}", name);
+    }
+}
+
+/// Pushes HEAD of the "repo" argument with `git push`, having `git-remote-nip` fail rather than
+/// prompt, and prints the index and URL the remote ended up with. Exits with git's status if the
+/// push fails.
+fn handle_ci_publish(matches: &ArgMatches, ipns_cache: &mut IpnsCache, ipfs: &mut IpfsClient) {
+    let repo = open_repo_arg(matches);
+    let remote_name = matches.value_of("remote").unwrap_or("origin");
+
+    let head = repo.head().unwrap_or_else(|e| {
+        error!("Could not find HEAD: {}", e);
+        process::exit(1);
+    });
+    let git_hash = head
+        .peel(ObjectType::Commit)
+        .map(|commit| commit.id().to_string())
+        .unwrap_or_else(|e| {
+            error!("HEAD doesn't point at a commit: {}", e);
+            process::exit(1);
+        });
+
+    let dst = match (matches.value_of("ref"), head.is_branch()) {
+        (Some(dst), _) if dst.starts_with("refs/") => dst.to_owned(),
+        (Some(dst), _) => format!("refs/heads/{}", dst),
+        (None, true) => head.name().unwrap().to_owned(),
+        (None, false) => {
+            error!("HEAD is detached, pass --ref to choose where to push it");
+            process::exit(1);
+        }
+    };
+
+    let started_at = session::unix_time();
+
+    // git's output goes to stderr so that stdout only has the result on it
+    let status = process::Command::new("git")
+        .arg("--git-dir")
+        .arg(repo.path())
+        .args(&["push", "--porcelain", remote_name])
+        .arg(format!("HEAD:{}", dst))
+        .env("GIT_TERMINAL_PROMPT", "0")
+        .stdin(process::Stdio::null())
+        .stdout(process::Stdio::null())
+        .status()
+        .unwrap_or_else(|e| {
+            error!("Could not run git push: {}", e);
+            process::exit(1);
+        });
+    if !status.success() {
+        error!("git push {} HEAD:{} failed ({})", remote_name, dst, status);
+        process::exit(status.code().unwrap_or(1));
+    }
+
+    let url = repo
+        .find_remote(remote_name)
+        .ok()
+        .and_then(|remote| remote.url().map(|url| url.to_owned()))
+        .unwrap_or_else(|| {
+            error!("Remote {} has no URL", remote_name);
+            process::exit(1);
+        });
+    let remote_state = NipState::load(&repo)
+        .map(|mut state| state.remote_mut(remote_name).clone())
+        .unwrap_or_default();
+
+    // A push that changed nothing doesn't record anything, the remote is where it was
+    let published_url = remote_state.pending_url.unwrap_or(url);
+    let nip_remote: NIPRemote = published_url
+        .splitn(2, "::")
+        .last()
+        .unwrap()
+        .parse()
+        .unwrap_or_else(|e| {
+            error!("Invalid nip URL {}: {}", published_url, e);
+            process::exit(1);
+        });
+    let index = match remote_state.last_push {
+        Some(ref last_push) if last_push.at >= started_at => last_push.index.clone(),
+        _ => resolve_to_ipfs_path(&nip_remote, ipns_cache, ipfs).unwrap_or_else(|e| {
+            error!("{}", e);
+            process::exit(1);
+        }),
+    };
+    let ipns_name = match nip_remote {
+        NIPRemote::ExistingIPNS(_) => nip_remote.get_hash(),
+        _ => None,
+    };
+
+    if matches.is_present("json") {
+        print_json(&serde_json::json!({
+            "remote": remote_name,
+            "ref": dst,
+            "git_hash": git_hash,
+            "index": index,
+            "ipns": ipns_name,
+            "url": published_url,
+        }));
+    } else {
+        println!("{}", index);
+        if let Some(ipns_name) = ipns_name {
+            println!("{}", ipns_name);
+        }
+    }
+}
+
+/// The outcome of a single `nipctl doctor` check
+enum DoctorCheck {
+    Ok(String),
+    /// Something that may cause trouble, with a suggested fix
+    Warn(String, String),
+    /// Something that will break nip, with a suggested fix
+    Fail(String, String),
+}
+
+/// Checks the IPFS daemon, the remote helper installation and the nip remotes of a local repo,
+/// printing out what to do about every problem found.
+fn handle_doctor(matches: &ArgMatches, config: &NipConfig) {
+    let mut checks = Vec::new();
+
+    let endpoint = format!("{}:{}", config.api_host, config.api_port);
+
+    // IPFS daemon reachability and version
+    let ipfs = config.ipfs_client();
+    let mut event_loop = Runtime::new().unwrap();
+
+    let mut daemon_ok = false;
+    match ipfs {
+        Ok(ref ipfs) => match event_loop.block_on(ipfs.version()) {
+            Ok(version) => {
+                daemon_ok = true;
+                checks.push(DoctorCheck::Ok(format!(
+                    "IPFS daemon reachable, version {} (commit {})",
+                    version.version, version.commit
+                )));
+            }
+            Err(IpfsError::Api(e)) => checks.push(DoctorCheck::Fail(
+                format!("IPFS API rejected the request: {}", e.message),
+                format!("Make sure the API at {} accepts requests from nip (API.HTTPHeaders, authentication proxies)", endpoint),
+            )),
+            Err(IpfsError::Client(e)) => checks.push(DoctorCheck::Fail(
+                format!("Could not connect to the IPFS daemon: {}", e),
+                format!("Start it with `ipfs daemon` or point nip.apiHost/nip.apiPort at it, nip expects the API at {}", endpoint),
+            )),
+            Err(e) => checks.push(DoctorCheck::Fail(
+                format!("Unexpected response from the IPFS daemon: {}", e),
+                format!("Check that {} is an IPFS API endpoint", endpoint),
+            )),
+        },
+        Err(ref e) => checks.push(DoctorCheck::Fail(
+            format!("Could not set up an IPFS client: {}", e),
+            "Check nip.apiHost and nip.apiPort".to_owned(),
+        )),
+    }
+
+    // IPNS key availability
+    if let (true, Ok(ipfs)) = (daemon_ok, ipfs) {
+        match event_loop.block_on(ipfs.key_list()) {
+            Ok(key_list) => {
+                if key_list.keys.iter().any(|key| key.name == "self") {
+                    checks.push(DoctorCheck::Ok(format!(
+                        "IPNS keys available: {}",
+                        key_list
+                            .keys
+                            .iter()
+                            .map(|key| key.name.as_str())
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    )));
+                } else {
+                    checks.push(DoctorCheck::Warn(
+                        "The \"self\" IPNS key is missing".to_owned(),
+                        "nip::new-ipns remotes publish under \"self\", run `ipfs init` on a fresh repo or restore the key".to_owned(),
+                    ));
+                }
+            }
+            Err(e) => checks.push(DoctorCheck::Warn(
+                format!("Could not list IPNS keys: {}", e),
+                "IPNS remotes won't work until `ipfs key list` succeeds".to_owned(),
+            )),
+        }
+    }
+
+    // Remote helper binary on PATH
+    let paths: Vec<_> = env::var_os("PATH")
+        .map(|path| env::split_paths(&path).collect())
+        .unwrap_or_else(Vec::new);
+
+    let helper_on_path = |name: &str| paths.iter().any(|dir| dir.join(name).is_file());
+
+    if helper_on_path("git-remote-nip") {
+        checks.push(DoctorCheck::Ok("git-remote-nip found on PATH".to_owned()));
+    } else {
+        checks.push(DoctorCheck::Fail(
+            "git-remote-nip is not on PATH, git won't be able to use nip:: remotes".to_owned(),
+            "Run `cargo install nip` or add ~/.cargo/bin to PATH".to_owned(),
+        ));
+    }
+
+    // nip remotes of the local repo
+    let repo_path = matches.value_of("repo").unwrap_or(".");
+    match Repository::discover(repo_path) {
+        Ok(repo) => match repo.remotes() {
+            Ok(remote_names) => {
+                for remote_name in remote_names.iter().flatten() {
+                    let remote = match repo.find_remote(remote_name) {
+                        Ok(remote) => remote,
+                        Err(_) => continue,
+                    };
+
+                    let url = match remote.url() {
+                        Some(url) => url,
+                        None => continue,
+                    };
+
+                    let (helper, link) = if let Some(link) = url.strip_prefix("nipdev::") {
+                        ("git-remote-nipdev", link)
+                    } else if let Some(link) = url.strip_prefix("nip::") {
+                        ("git-remote-nip", link)
+                    } else {
+                        continue;
+                    };
+
+                    match link.parse::<NIPRemote>() {
+                        Ok(_) => checks.push(DoctorCheck::Ok(format!(
+                            "Remote {} has a valid URL {}",
+                            remote_name, url
+                        ))),
+                        Err(e) => checks.push(DoctorCheck::Fail(
+                            format!("Remote {} has an invalid URL {}: {}", remote_name, url, e),
+                            format!(
+                                "Use one of nip::new-ipfs, nip::new-ipns, nip::/ipfs/<hash> or nip::/ipns/<hash>, e.g. `git remote set-url {} nip::new-ipfs`",
+                                remote_name
+                            ),
+                        )),
+                    }
+
+                    if helper != "git-remote-nip" && !helper_on_path(helper) {
+                        checks.push(DoctorCheck::Warn(
+                            format!("Remote {} needs {}, which is not on PATH", remote_name, helper),
+                            "Run dev_bootstrap.sh from the nip source tree".to_owned(),
+                        ));
+                    }
+                }
+            }
+            Err(e) => checks.push(DoctorCheck::Warn(
+                format!("Could not list the remotes of {}: {}", repo_path, e),
+                "Check the repo's .git/config".to_owned(),
+            )),
+        },
+        Err(_) => debug!("{} is not a git repo, skipping remote checks", repo_path),
+    }
+
+    if matches.is_present("json") {
+        let checks_json: Vec<_> = checks
+            .iter()
+            .map(|check| match check {
+                DoctorCheck::Ok(msg) => serde_json::json!({ "status": "ok", "message": msg }),
+                DoctorCheck::Warn(msg, fix) => {
+                    serde_json::json!({ "status": "warn", "message": msg, "fix": fix })
+                }
+                DoctorCheck::Fail(msg, fix) => {
+                    serde_json::json!({ "status": "fail", "message": msg, "fix": fix })
+                }
+            })
+            .collect();
+        print_json(&serde_json::json!({ "checks": checks_json }));
+    }
+
+    let mut failed = false;
+    for check in checks {
+        if matches.is_present("json") {
+            failed |= match check {
+                DoctorCheck::Fail(..) => true,
+                _ => false,
+            };
+            continue;
+        }
+
+        match check {
+            DoctorCheck::Ok(msg) => println!("[{}] {}", " OK ".green(), msg),
+            DoctorCheck::Warn(msg, fix) => {
+                println!("[{}] {}", "WARN".yellow(), msg);
+                println!("       {} {}", "Fix:".bold(), fix);
+            }
+            DoctorCheck::Fail(msg, fix) => {
+                failed = true;
+                println!("[{}] {}", "FAIL".red(), msg);
+                println!("       {} {}", "Fix:".bold(), fix);
+            }
+        }
+    }
+
+    if failed {
+        process::exit(1);
+    }
+}
@@ -0,0 +1,282 @@
+//! Per-repo nip state kept under `.git/nip/`
+use failure::Error;
+use git2::Repository;
+
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Returns the directory shared by all worktrees of `repo`, i.e. the one with objects, refs and
+/// config in it.
+pub fn common_dir(repo: &Repository) -> PathBuf {
+    match fs::read_to_string(repo.path().join("commondir")) {
+        Ok(rel_path) => repo.path().join(rel_path.trim()),
+        Err(_) => repo.path().to_owned(),
+    }
+}
+
+/// Returns the directory nip keeps its per-repo state in, creating it if necessary.
+pub fn nip_dir(repo: &Repository) -> Result<PathBuf, Error> {
+    let dir = common_dir(repo).join("nip");
+    fs::create_dir_all(&dir)
+        .map_err(|e| format_err!("Could not create {}: {}", dir.display(), e))?;
+    Ok(dir)
+}
+
+/// Seconds since the Unix epoch
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+/// Replaces `path` with `contents` so that concurrent readers never see a partial file
+pub fn write_atomically(path: &Path, contents: &str) -> Result<(), Error> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, contents)?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// An exclusive lock on a repo's nip state for the duration of a helper session. The lock file
+/// contains the holder's PID and is removed on drop.
+#[derive(Debug)]
+pub struct SessionLock {
+    path: PathBuf,
+}
+
+impl SessionLock {
+    /// Takes the lock for `repo`. Locks left behind by processes that don't exist anymore are
+    /// taken over.
+    pub fn acquire(repo: &Repository) -> Result<Self, Error> {
+        let path = nip_dir(repo)?.join("session.lock");
+
+        match Self::try_create(&path) {
+            Ok(lock) => Ok(lock),
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                let holder = read_holder(&path);
+
+                match holder {
+                    Some(pid) if !process_alive(pid) => {
+                        warn!("Taking over stale lock {} left by PID {}", path.display(), pid);
+                        Self::take_over(&path, pid)
+                    }
+                    _ => Err(Self::held_error(&path, holder)),
+                }
+            }
+            Err(e) => bail!("Could not create lock file {}: {}", path.display(), e),
+        }
+    }
+
+    fn try_create(path: &Path) -> io::Result<Self> {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)?;
+        writeln!(file, "{}", process::id())?;
+
+        debug!("Took session lock {}", path.display());
+
+        Ok(Self {
+            path: path.to_owned(),
+        })
+    }
+
+    /// Replaces the lock at `path` held by the dead `stale_pid` with our own. Only one process
+    /// at a time may do so, the others find out through the `.takeover` file next to the lock;
+    /// whoever comes second sees that the lock isn't `stale_pid`'s anymore and backs off.
+    fn take_over(path: &Path, stale_pid: u32) -> Result<Self, Error> {
+        let guard_path = path.with_extension("lock.takeover");
+        let guard = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&guard_path);
+        if let Err(e) = guard {
+            if e.kind() == io::ErrorKind::AlreadyExists {
+                bail!(
+                    "Another nip process is taking over {}. If none is running, delete {} and try again.",
+                    path.display(),
+                    guard_path.display()
+                );
+            }
+            bail!("Could not create {}: {}", guard_path.display(), e);
+        }
+
+        let res = match read_holder(path) {
+            Some(pid) if pid == stale_pid => {
+                // Rename replaces the stale lock in one step, there's no moment without a lock
+                let tmp_path = path.with_extension(format!("lock.{}.tmp", process::id()));
+                fs::write(&tmp_path, format!("{}\n", process::id()))
+                    .and_then(|()| fs::rename(&tmp_path, path))
+                    .map(|()| Self {
+                        path: path.to_owned(),
+                    })
+                    .map_err(|e| format_err!("Could not take over {}: {}", path.display(), e))
+            }
+            // Released in the meantime, so it's up for grabs like any other time
+            None if !path.exists() => Self::try_create(path).map_err(|e| {
+                format_err!("Could not create lock file {}: {}", path.display(), e)
+            }),
+            holder => Err(Self::held_error(path, holder)),
+        };
+
+        if let Err(e) = fs::remove_file(&guard_path) {
+            warn!("Could not remove {}: {}", guard_path.display(), e);
+        }
+
+        if res.is_ok() {
+            debug!("Took over session lock {}", path.display());
+        }
+        res
+    }
+
+    fn held_error(path: &Path, holder: Option<u32>) -> Error {
+        format_err!(
+            "Another nip operation is in progress ({} is held by PID {}). If no other nip process is running, delete the lock file and try again.",
+            path.display(),
+            holder.map_or_else(|| "<unknown>".to_owned(), |pid| pid.to_string())
+        )
+    }
+}
+
+impl Drop for SessionLock {
+    fn drop(&mut self) {
+        match fs::remove_file(&self.path) {
+            Ok(()) => debug!("Released session lock {}", self.path.display()),
+            Err(e) => warn!("Could not remove lock file {}: {}", self.path.display(), e),
+        }
+    }
+}
+
+/// Reads the PID of the process holding the lock at `path`
+fn read_holder(path: &Path) -> Option<u32> {
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|contents| contents.trim().parse::<u32>().ok())
+}
+
+/// Checks whether `pid` is alive, with procfs where there is one and `ps` on other Unix systems.
+/// When neither can tell, every process is assumed alive so that a lock is never stolen by
+/// mistake.
+fn process_alive(pid: u32) -> bool {
+    let proc_dir = Path::new("/proc");
+    if proc_dir.join("self").is_dir() {
+        return proc_dir.join(pid.to_string()).exists();
+    }
+
+    if cfg!(unix) {
+        // POSIX ps exits with a failure status when no process matches
+        if let Ok(status) = process::Command::new("ps")
+            .args(&["-p", &pid.to_string()])
+            .stdout(process::Stdio::null())
+            .stderr(process::Stdio::null())
+            .status()
+        {
+            return status.success();
+        }
+    }
+
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{env, ops::Deref};
+
+    /// A repo in a temp dir of its own, deleted on drop
+    struct TempRepo {
+        path: PathBuf,
+        repo: Repository,
+    }
+
+    impl Deref for TempRepo {
+        type Target = Repository;
+
+        fn deref(&self) -> &Repository {
+            &self.repo
+        }
+    }
+
+    impl Drop for TempRepo {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.path);
+        }
+    }
+
+    /// Creates an empty repo in a temp dir of its own
+    fn temp_repo(name: &str) -> TempRepo {
+        let path = env::temp_dir().join(format!("nip-session-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&path);
+        let repo = Repository::init(&path).unwrap();
+        TempRepo { path, repo }
+    }
+
+    fn lock_path(repo: &Repository) -> PathBuf {
+        nip_dir(repo).unwrap().join("session.lock")
+    }
+
+    #[test]
+    fn lock_is_exclusive() {
+        let repo = temp_repo("exclusive");
+        let _lock = SessionLock::acquire(&repo).unwrap();
+
+        assert!(SessionLock::acquire(&repo).is_err());
+    }
+
+    #[test]
+    fn lock_is_released_on_drop() {
+        let repo = temp_repo("drop");
+        drop(SessionLock::acquire(&repo).unwrap());
+
+        assert!(!lock_path(&repo).exists());
+        assert!(SessionLock::acquire(&repo).is_ok());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stale_lock_is_taken_over() {
+        let repo = temp_repo("stale");
+        let mut child = process::Command::new("true").spawn().unwrap();
+        let stale_pid = child.id();
+        child.wait().unwrap();
+        fs::write(lock_path(&repo), format!("{}\n", stale_pid)).unwrap();
+
+        let _lock = SessionLock::acquire(&repo).unwrap();
+
+        assert_eq!(read_holder(&lock_path(&repo)), Some(process::id()));
+        assert!(!lock_path(&repo).with_extension("lock.takeover").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn concurrent_takeover_backs_off() {
+        let repo = temp_repo("takeover");
+        let mut child = process::Command::new("true").spawn().unwrap();
+        let stale_pid = child.id();
+        child.wait().unwrap();
+        fs::write(lock_path(&repo), format!("{}\n", stale_pid)).unwrap();
+        fs::write(lock_path(&repo).with_extension("lock.takeover"), "").unwrap();
+
+        assert!(SessionLock::acquire(&repo).is_err());
+        assert_eq!(read_holder(&lock_path(&repo)), Some(stale_pid));
+    }
+
+    #[test]
+    fn live_holder_is_respected() {
+        let repo = temp_repo("live");
+        fs::write(lock_path(&repo), format!("{}\n", process::id())).unwrap();
+
+        assert!(SessionLock::acquire(&repo).is_err());
+        assert_eq!(read_holder(&lock_path(&repo)), Some(process::id()));
+    }
+}
@@ -0,0 +1,89 @@
+//! What nip remembers about each remote between sessions, kept in `.git/nip/state`
+use failure::Error;
+use git2::Repository;
+
+use std::{collections::BTreeMap, fs, io};
+
+use crate::session;
+
+/// The contents of the state file
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct NipState {
+    /// Per-remote state keyed by remote name
+    #[serde(default)]
+    pub remotes: BTreeMap<String, RemoteState>,
+}
+
+/// Everything recorded about a single remote
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct RemoteState {
+    /// The `/ipfs/` path of the last index successfully obtained through the remote's IPNS name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_good_index: Option<String>,
+    /// The index the last fetch from the remote used
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_fetch: Option<IndexObservation>,
+    /// The index the last push to the remote produced
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_push: Option<IndexObservation>,
+    /// The nip URL of the last push when it wasn't written to the remote's URL, see
+    /// `nip.updateRemoteUrl`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pending_url: Option<String>,
+}
+
+/// A remote index generation as seen at a given time
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct IndexObservation {
+    /// The `/ipfs/` path of the index
+    pub index: String,
+    /// Seconds since the Unix epoch
+    pub at: u64,
+}
+
+impl IndexObservation {
+    /// Records seeing `index` right now
+    pub fn now(index: &str) -> Self {
+        Self {
+            index: index.to_owned(),
+            at: session::unix_time(),
+        }
+    }
+}
+
+impl NipState {
+    /// Reads the state of `repo`; a repo nip hasn't worked with yet has an empty one.
+    pub fn load(repo: &Repository) -> Result<Self, Error> {
+        let path = session::nip_dir(repo)?.join("state");
+
+        match fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .map_err(|e| format_err!("Could not parse {}: {}", path.display(), e)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => bail!("Could not read {}: {}", path.display(), e),
+        }
+    }
+
+    /// Writes the state back to `repo`
+    pub fn save(&self, repo: &Repository) -> Result<(), Error> {
+        let path = session::nip_dir(repo)?.join("state");
+
+        session::write_atomically(&path, &serde_json::to_string_pretty(self)?)
+            .map_err(|e| format_err!("Could not write {}: {}", path.display(), e))
+    }
+
+    /// Applies `update` to the state of `remote_name` on disk
+    pub fn update<F>(repo: &Repository, remote_name: &str, update: F) -> Result<(), Error>
+    where
+        F: FnOnce(&mut RemoteState),
+    {
+        let mut state = Self::load(repo)?;
+        update(state.remote_mut(remote_name));
+        state.save(repo)
+    }
+
+    /// Returns the state of `remote_name`, creating an empty one if necessary
+    pub fn remote_mut(&mut self, remote_name: &str) -> &mut RemoteState {
+        self.remotes.entry(remote_name.to_owned()).or_default()
+    }
+}
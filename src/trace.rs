@@ -0,0 +1,149 @@
+//! `GIT_TRACE`-style tracing of the remote helper, enabled by `GIT_TRACE_NIP`. Like git's own
+//! trace variables, it takes `1`, `2` or `true` for stderr and an absolute path for a file to
+//! append to; anything else, including `0` and `false`, leaves tracing off.
+use std::{
+    env,
+    fmt::Display,
+    fs::OpenOptions,
+    io::{self, BufRead, Read, Write},
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// The environment variable controlling the trace
+pub static TRACE_VAR: &str = "GIT_TRACE_NIP";
+
+enum TraceTarget {
+    Stderr,
+    File(PathBuf),
+}
+
+fn target() -> Option<TraceTarget> {
+    let val = env::var(TRACE_VAR).ok()?;
+    match val.to_lowercase().as_str() {
+        "1" | "2" | "true" => Some(TraceTarget::Stderr),
+        path if path.starts_with('/') => Some(TraceTarget::File(PathBuf::from(val))),
+        _ => None,
+    }
+}
+
+/// Checks whether `GIT_TRACE_NIP` asks for tracing
+pub fn enabled() -> bool {
+    target().is_some()
+}
+
+/// Writes `msg` to the trace, prefixed with the time of day like git does
+pub fn trace(msg: &str) {
+    let target = match target() {
+        Some(target) => target,
+        None => return,
+    };
+
+    let since_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let secs = since_epoch.as_secs() % (24 * 60 * 60);
+    let line = format!(
+        "{:02}:{:02}:{:02}.{:06} nip: {}\n",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+        since_epoch.subsec_micros(),
+        msg
+    );
+
+    // Tracing is best effort, it must never affect the session itself
+    let _ = match target {
+        TraceTarget::Stderr => io::stderr().write_all(line.as_bytes()),
+        TraceTarget::File(path) => OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .and_then(|mut file| file.write_all(line.as_bytes())),
+    };
+}
+
+/// Traces the IPFS operation described by `request` along with how it went, passing `res` on
+pub fn ipfs<T, E: Display>(request: &str, res: Result<T, E>) -> Result<T, E> {
+    if enabled() {
+        trace(&format!("ipfs> {}", request));
+        match res {
+            Ok(_) => trace("ipfs< ok"),
+            Err(ref e) => trace(&format!("ipfs< error: {}", e)),
+        }
+    }
+    res
+}
+
+/// Traces every line read from git as `git> <line>`
+pub struct TracedReader<R> {
+    inner: R,
+}
+
+impl<R> TracedReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self { inner }
+    }
+}
+
+impl<R: Read> Read for TracedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<R: BufRead> BufRead for TracedReader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.inner.consume(amt)
+    }
+
+    fn read_line(&mut self, buf: &mut String) -> io::Result<usize> {
+        let start = buf.len();
+        let len = self.inner.read_line(buf)?;
+        if len > 0 {
+            trace(&format!("git> {}", buf[start..].trim_end_matches('\n')));
+        }
+        Ok(len)
+    }
+}
+
+/// Traces every line written to git as `git< <line>`
+pub struct TracedWriter<W> {
+    inner: W,
+    /// The part of the current line written so far
+    line: Vec<u8>,
+}
+
+impl<W> TracedWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            line: Vec::new(),
+        }
+    }
+}
+
+impl<W: Write> Write for TracedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+
+        for &byte in &buf[..len] {
+            if byte == b'\n' {
+                trace(&format!("git< {}", String::from_utf8_lossy(&self.line)));
+                self.line.clear();
+            } else {
+                self.line.push(byte);
+            }
+        }
+
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}